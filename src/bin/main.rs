@@ -1,24 +1,173 @@
 use indicatif::ProgressBar;
 use lazy_static::lazy_static;
 use mikack::{
-    extractors::{self, DomainRoute, Extractor},
+    extractors::{self, DomainRoute},
     models::*,
 };
-use mikack_cli::{exporters, *};
-use std::collections::HashMap;
+use mikack_cli::{
+    downloader::{Progress, Session},
+    exporters,
+    history::{self, History},
+    library::Library,
+    output::{self, report},
+    queue::{Job, Queue},
+    ratelimit::RateLimit,
+    server,
+    settings::{self, Settings},
+    tasks::{self, get_exrt, ExtractorObject, Target},
+    *,
+};
+use serde_json::json;
+use std::fs;
+use std::io::{stdin, Read};
+use std::path::Path;
+use std::process;
 use std::sync::Mutex;
 
 lazy_static! {
-    static ref CONFIG: Mutex<HashMap<&'static str, String>> = Mutex::new(HashMap::new());
+    static ref CONFIG: Mutex<Settings> = Mutex::new(Settings::default());
+}
+
+fn main() {
+    if let Err(e) = run() {
+        report("error", json!({ "message": e.to_string() }), || {
+            eprintln!("Error: {}", e)
+        });
+        process::exit(1);
+    }
 }
 
-fn main() -> Result<()> {
+fn run() -> Result<()> {
     let matches = cli::build_cli().get_matches();
-    if let Some(format) = matches.value_of("save-format") {
-        CONFIG.lock().unwrap().insert("format", format.to_string());
+    // 全局参数在子命令中同样可用
+    let (name, sub_matches) = matches.subcommand();
+    let args = sub_matches.unwrap_or(&matches);
+    output::set_json(args.is_present("json"));
+    let mut settings = Settings::load(args.value_of("config"))?;
+    apply_args(&mut settings, args)?;
+    let session = Session::new(&settings.client_options())?;
+    let rule = settings.chapters.clone();
+    *CONFIG.lock().unwrap() = settings;
+    if let Some(input) = matches.value_of("input") {
+        if sub_matches.is_some() {
+            return Err(err_msg(format!(
+                "--input cannot be used with the {} subcommand, use `download --input` to download only",
+                name
+            )));
+        }
+        return process_batch(&session, input, true, rule.as_deref());
+    }
+    match (name, sub_matches) {
+        ("platforms", Some(_)) => process_platforms(),
+        ("info", Some(m)) => process_info(&session, m.value_of("url").unwrap()),
+        ("download", Some(m)) => match m.value_of("input") {
+            Some(input) => process_batch(&session, input, false, rule.as_deref()),
+            None => process_url(&session, m.value_of("url").unwrap(), false, rule.as_deref()),
+        },
+        ("cached", Some(_)) => process_cached(),
+        ("migrate-cache", Some(_)) => process_migrate_cache(),
+        ("export", Some(m)) => {
+            if m.is_present("all") {
+                let cache_dir = CONFIG.lock().unwrap().cache_dir.clone();
+                let base_dirs = exporters::cached_chapters(&cache_dir)?
+                    .into_iter()
+                    .filter(|(_, metadata)| metadata.pending.is_empty())
+                    .map(|(base_dir, _)| base_dir)
+                    .collect::<Vec<_>>();
+                process_export_cached(&base_dirs.iter().map(|d| d.as_str()).collect::<Vec<_>>())
+            } else {
+                process_export_cached(&m.values_of("cached-chapter").unwrap().collect::<Vec<_>>())
+            }
+        }
+        ("follow", Some(m)) => process_follow(
+            &session,
+            m.value_of("url").unwrap(),
+            m.is_present("skip-existing"),
+        ),
+        ("unfollow", Some(m)) => process_unfollow(m.value_of("url").unwrap()),
+        ("check", Some(_)) => process_check(&session),
+        ("history", Some(m)) => process_history(m),
+        ("update", Some(_)) => process_update(&session),
+        ("queue", Some(m)) => process_queue(&session, m, rule.as_deref()),
+        ("serve", Some(m)) => process_serve(
+            &session,
+            m.value_of("listen").unwrap_or(server::DEFAULT_LISTEN),
+            m.value_of("allow-origin"),
+        ),
+        ("search", Some(m)) => process_search(
+            &session,
+            m.value_of("platform").unwrap(),
+            m.value_of("keywords").unwrap(),
+        ),
+        ("index", Some(m)) => process_index_list(
+            &session,
+            m.value_of("platform").unwrap(),
+            m.value_of("page").unwrap_or("1").parse()?,
+        ),
+        _ => {
+            if let Some(url) = matches.value_of("url") {
+                return process_url(&session, url, true, rule.as_deref());
+            }
+            process_interactive(&session, rule.as_deref())
+        }
+    }
+}
+
+/// 命令行参数覆盖配置文件中的设置
+fn apply_args(settings: &mut Settings, args: &clap::ArgMatches) -> Result<()> {
+    if let Some(format) = args.value_of("save-format") {
+        settings.format = Some(format.to_string());
+    }
+    if let Some(chapters) = args.value_of("chapters") {
+        settings.chapters = Some(chapters.to_string());
+    }
+    if let Some(volume) = args.value_of("volume") {
+        settings.volume = Some(volume.to_string());
+    }
+    if let Some(dir) = args.value_of("output-dir") {
+        settings.output_dir = settings::expand_home(Path::new(dir));
+    }
+    if let Some(dir) = args.value_of("cache-dir") {
+        settings.cache_dir = settings::expand_home(Path::new(dir));
+    }
+    if let Some(jobs) = args.value_of("jobs") {
+        settings.jobs = jobs.parse()?;
+    }
+    if let Some(n) = args.value_of("max-attempts") {
+        settings.max_attempts = Some(n.parse()?);
+    }
+    if let Some(secs) = args.value_of("timeout") {
+        settings.timeout = secs.parse()?;
+    }
+    if let Some(rps) = args.value_of("rate-limit") {
+        let limit = RateLimit::per_second(rps.parse()?);
+        settings.rate_limit = Some(limit);
+        settings.image_rate_limit = Some(limit);
+    }
+    if let Some(secs) = args.value_of("connect-timeout") {
+        settings.connect_timeout = Some(secs.parse()?);
+    }
+    if let Some(user_agent) = args.value_of("user-agent") {
+        settings.user_agent = Some(user_agent.to_string());
     }
-    if let Some(url) = matches.value_of("url") {
-        return process_url(url);
+    if let Some(proxy) = args.value_of("proxy") {
+        settings.proxy = Some(proxy.to_string());
+    }
+    if args.is_present("insecure") {
+        settings.insecure = true;
+    }
+    if args.is_present("rtl") {
+        settings.rtl = true;
+    }
+    if args.is_present("skip-downloaded") {
+        settings.skip_downloaded = true;
+    }
+    Ok(())
+}
+
+fn process_interactive(session: &Session, rule: Option<&str>) -> Result<()> {
+    if output::is_json() {
+        return Err(err_msg("Interactive mode is not available with --json"));
     }
     let mut domains = vec![];
     for (i, (domain, name)) in extractors::PLATFORMS.iter().enumerate() {
@@ -28,104 +177,803 @@ fn main() -> Result<()> {
 
     let platform_s = read_input_as_string("\nPlease enter platform number: ")?;
     let domain = domains[platform_s.parse::<usize>()? - 1];
-    let extractor =
-        extractors::get_extr(domain).expect(&format!("Unsupported platform {}", domain));
-    process_index(extractor, 1)?;
+    process_index(session, domain, 1, rule)?;
     Ok(())
 }
 
-fn process_url(url: &str) -> Result<()> {
-    let domain_route = extractors::domain_route(url).expect("This link is not supported");
-    Ok(match domain_route {
-        DomainRoute::Comic(domain) => {
-            process_chapters(get_exrt(domain)?, &mut Comic::new("", url))?
+/// 请求平台的页面前按限速等待
+fn throttle(session: &Session, host: &str) {
+    let limit = CONFIG.lock().unwrap().rate_limit(host);
+    session.throttle(host, limit.as_ref());
+}
+
+fn process_platforms() -> Result<()> {
+    let platforms = extractors::PLATFORMS
+        .iter()
+        .map(|(domain, name)| json!({ "domain": domain, "name": name }))
+        .collect::<Vec<_>>();
+    report("platforms", json!({ "platforms": platforms }), || {
+        for (domain, name) in extractors::PLATFORMS.iter() {
+            println!("{}\t{}", domain, name);
         }
-        DomainRoute::Chapter(domain) => {
-            process_save(get_exrt(domain)?, &mut Chapter::new("", url, 0))?
+    });
+    Ok(())
+}
+
+fn process_info(session: &Session, url: &str) -> Result<()> {
+    match tasks::resolve(url)? {
+        (extractor, Target::Comic(mut comic)) => {
+            let spinner = create_spinner("Fetching...");
+            throttle(session, &platform(url));
+            extractor.fetch_chapters(&mut comic)?;
+            spinner.finish_and_clear();
+            report_chapters(&comic, || {
+                println!("Title: {}", comic.title);
+                println!("URL: {}", comic.url);
+                println!("Chapters: {}", comic.chapters.len());
+                for (i, chapter) in comic.chapters.iter().enumerate() {
+                    println!("{}. {} ({})", i + 1, chapter.title, chapter.url);
+                }
+            });
         }
-    })
+        (extractor, Target::Chapter(mut chapter)) => {
+            let spinner = create_spinner("Fetching...");
+            throttle(session, &platform(url));
+            let pages_iter = extractor.pages_iter(&mut chapter)?;
+            spinner.finish_and_clear();
+            let title = pages_iter.chapter_title_clone();
+            report(
+                "chapter",
+                json!({ "title": title, "url": url, "pages": pages_iter.total }),
+                || {
+                    println!("Title: {}", title);
+                    println!("URL: {}", url);
+                    println!("Pages: {}", pages_iter.total);
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+fn process_search(session: &Session, domain: &str, keywords: &str) -> Result<()> {
+    let extractor = get_exrt(domain.to_string())?;
+    let spinner = create_spinner("Searching...");
+    throttle(session, domain);
+    let comics = extractor.search(keywords)?;
+    spinner.finish_and_clear();
+    report_comics("search", json!({ "keywords": keywords }), &comics);
+    Ok(())
+}
+
+fn process_index_list(session: &Session, domain: &str, index: usize) -> Result<()> {
+    let extractor = get_exrt(domain.to_string())?;
+    let spinner = create_spinner("Fetching...");
+    throttle(session, domain);
+    let comics = extractor.index(index as u32)?;
+    spinner.finish_and_clear();
+    report_comics("index", json!({ "page": index }), &comics);
+    Ok(())
 }
 
-type ExtractorObject = Box<dyn Extractor + Sync + Send>;
+/// 输出漫画列表，extra 为 JSON 事件的附加字段
+fn report_comics(event: &str, extra: serde_json::Value, comics: &[Comic]) {
+    let mut data = extra;
+    data["comics"] = comics
+        .iter()
+        .map(|comic| json!({ "title": comic.title, "url": comic.url }))
+        .collect();
+    report(event, data, || {
+        for (i, comic) in comics.iter().enumerate() {
+            println!("{}. {} ({})", i + 1, comic.title, comic.url);
+        }
+    });
+}
 
-fn get_exrt(domain: String) -> Result<&'static ExtractorObject> {
-    if let Some(extractor) = extractors::get_extr(&domain) {
-        Ok(extractor)
+/// 输出漫画的章节列表
+fn report_chapters<F: FnOnce()>(comic: &Comic, text: F) {
+    let chapters = comic
+        .chapters
+        .iter()
+        .enumerate()
+        .map(|(i, chapter)| json!({ "index": i + 1, "title": chapter.title, "url": chapter.url }))
+        .collect::<Vec<_>>();
+    report(
+        "chapters",
+        json!({ "title": comic.title, "url": comic.url, "chapters": chapters }),
+        text,
+    );
+}
+
+/// 下载链接对应的章节，漫画主页按 rule 选择章节（未指定时提示输入）
+fn process_url(session: &Session, url: &str, export: bool, rule: Option<&str>) -> Result<()> {
+    Ok(match tasks::resolve(url)? {
+        (extractor, Target::Comic(mut comic)) => {
+            process_chapters(session, extractor, &mut comic, export, rule)?
+        }
+        (extractor, Target::Chapter(mut chapter)) => {
+            if skip_downloaded(&chapter)? {
+                return Ok(());
+            }
+            let base_dir = process_save(session, extractor, &mut chapter, &Origin::default())?;
+            if export {
+                process_export(&[base_dir.as_str()])?
+            } else {
+                report_cached(&base_dir);
+            }
+        }
+    })
+}
+
+/// 批量处理链接列表。每行一个链接，链接后可以空白分隔指定章节规则（eg: `<url> last:3`），
+/// 空行及 `#` 开头的行会被忽略
+fn process_batch(
+    session: &Session,
+    input: &str,
+    export: bool,
+    default_rule: Option<&str>,
+) -> Result<()> {
+    let contents = if input == "-" {
+        let mut contents = String::new();
+        stdin().read_to_string(&mut contents)?;
+        contents
     } else {
-        Err(err_msg(format!("Unsupported platform {}", domain)))
+        fs::read_to_string(input)?
+    };
+    let mut succeeded = 0;
+    let mut failures = vec![];
+    let lines = contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    for line in lines {
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let url = parts.next().unwrap();
+        let rule = parts.next().map(|rule| rule.trim()).or(default_rule);
+        report("input", json!({ "url": url }), || println!("==> {}", url));
+        // 批量模式下不进行交互，漫画主页必须指定章节规则
+        let result = match (extractors::domain_route(url), rule) {
+            (None, _) => Err(err_msg("This link is not supported")),
+            (Some(DomainRoute::Comic(_)), None) => Err(err_msg(
+                "Missing chapter rule, append one to the line or use --chapters",
+            )),
+            (Some(_), rule) => process_url(session, url, export, rule),
+        };
+        match result {
+            Ok(()) => succeeded += 1,
+            Err(e) => {
+                report(
+                    "error",
+                    json!({ "url": url, "message": e.to_string() }),
+                    || eprintln!("Failed: {}: {}", url, e),
+                );
+                failures.push((url.to_string(), e.to_string()));
+            }
+        }
     }
+    let failed = failures
+        .iter()
+        .map(|(url, e)| json!({ "url": url, "message": e }))
+        .collect::<Vec<_>>();
+    report(
+        "summary",
+        json!({ "succeeded": succeeded, "failed": failed }),
+        || {
+            println!(
+                "Summary: {} succeeded, {} failed",
+                succeeded,
+                failures.len()
+            );
+            for (url, e) in &failures {
+                println!("  {}: {}", url, e);
+            }
+        },
+    );
+    if !failures.is_empty() {
+        return Err(err_msg(format!(
+            "{} of {} URLs failed",
+            failures.len(),
+            succeeded + failures.len()
+        )));
+    }
+    Ok(())
 }
 
-fn process_index(extractor: &ExtractorObject, index: usize) -> Result<()> {
+fn process_index(session: &Session, domain: &str, index: usize, rule: Option<&str>) -> Result<()> {
+    let extractor = get_exrt(domain.to_string())?;
     let spinner = create_spinner("Fetching...");
+    throttle(session, domain);
     let mut comics = extractor.index(index as u32)?;
     spinner.finish_and_clear();
-    for (i, comic) in comics.iter().enumerate() {
-        println!("{}. {}", i + 1, comic.title);
-    }
+    report_comics("index", json!({ "page": index }), &comics);
     let comic_s = read_input_as_string(&format!(
         "* p{}\nPlease enter comic number (or press Enter to turn pages): ",
         index
     ))?;
     if comic_s.is_empty() {
-        return process_index(extractor, index + 1);
+        return process_index(session, domain, index + 1, rule);
     }
     let comic = &mut comics[comic_s.parse::<usize>()? - 1];
-    process_chapters(extractor, comic)?;
+    process_chapters(session, extractor, comic, true, rule)?;
     Ok(())
 }
 
-fn process_chapters(extractor: &ExtractorObject, comic: &mut Comic) -> Result<()> {
+fn process_chapters(
+    session: &Session,
+    extractor: &ExtractorObject,
+    comic: &mut Comic,
+    export: bool,
+    rule: Option<&str>,
+) -> Result<()> {
+    let selects = select_comic_chapters(session, extractor, comic, rule)?;
+    // 选中的章节先加入队列，中断后可以通过 `queue run` 继续（合并为卷时同样在整卷完成后导出）
+    let ids = enqueue_chapters(comic, &selects, export)?;
+    run_queue(session, Some(&ids))
+}
+
+/// 获取并列出漫画的章节，按章节规则（或提示输入）选择章节，返回选中的序号
+fn select_comic_chapters(
+    session: &Session,
+    extractor: &ExtractorObject,
+    comic: &mut Comic,
+    rule: Option<&str>,
+) -> Result<Vec<usize>> {
+    let config = CONFIG.lock().unwrap().clone();
     let spinner = create_spinner("Fetching...");
-    extractor.fetch_chapters(comic)?;
+    let fetched = tasks::fetch_chapters(session, &config, extractor, comic);
     spinner.finish_and_clear();
-    for (i, chapter) in comic.chapters.iter().enumerate() {
-        println!("{}. {}", i + 1, chapter.title);
-    }
-    let chapter_s = read_input_as_string("\nPlease enter chapter number: ")?;
-    let selects = parse_select_rule(&chapter_s)?;
+    fetched?;
+    report_chapters(comic, || {
+        for (i, chapter) in comic.chapters.iter().enumerate() {
+            println!("{}. {}", i + 1, chapter.title);
+        }
+    });
+    let chapter_s = match rule {
+        Some(rule) => rule.to_string(),
+        None => read_input_as_string("\nPlease enter chapter number: ")?,
+    };
+    select_chapters(&chapter_s, comic.chapters.len())
+}
+
+/// 将选中的章节加入队列（跳过下载历史中已有的章节），返回任务编号
+fn enqueue_chapters(comic: &Comic, selects: &[usize], export: bool) -> Result<Vec<u64>> {
+    let config = CONFIG.lock().unwrap().clone();
+    let mut chapters = vec![];
     for n in selects {
-        let chapter = &mut comic.chapters[n - 1];
-        process_save(extractor, chapter)?;
+        if !skip_downloaded(&comic.chapters[n - 1])? {
+            chapters.push(*n);
+        }
+    }
+    tasks::enqueue_chapters(&config, comic, &chapters, export)
+}
+
+/// 依次执行队列中等待的任务，ids 为空时执行全部等待的任务。
+/// 单个任务失败不影响其它任务
+fn run_queue(session: &Session, ids: Option<&[u64]>) -> Result<()> {
+    let config = CONFIG.lock().unwrap().clone();
+    let start = |job: &Job| {
+        report(
+            "job",
+            json!({ "id": job.id, "title": job.title, "url": job.url }),
+            || println!("==> [{}] {}", job.id, job_name(job)),
+        );
+        CliProgress::new(&job.url)
+    };
+    let finish = |job: &Job, result: &Result<tasks::Done>| match result {
+        Ok(done) => {
+            report_resumed(done.saved.cached);
+            for (format, path) in &done.outputs {
+                report_exported(format, path);
+            }
+            if !job.export {
+                report_cached(&done.saved.base_dir);
+            }
+        }
+        Err(e) => report(
+            "error",
+            json!({ "id": job.id, "url": job.url, "message": e.to_string() }),
+            || eprintln!("Failed: [{}] {}: {}", job.id, job_name(job), e),
+        ),
+    };
+    let failed = tasks::run_queue(session, &config, ids, start, finish)?;
+    if failed > 0 {
+        return Err(err_msg(format!(
+            "{} jobs failed, run `queue retry` to retry them",
+            failed
+        )));
     }
     Ok(())
 }
 
-fn process_save(extractor: &ExtractorObject, chapter: &mut Chapter) -> Result<()> {
-    let page_headers = chapter.page_headers.clone();
+fn job_name(job: &Job) -> &str {
+    if job.title.is_empty() {
+        &job.url
+    } else {
+        &job.title
+    }
+}
+
+fn process_queue(session: &Session, matches: &clap::ArgMatches, rule: Option<&str>) -> Result<()> {
+    let data_dir = CONFIG.lock().unwrap().data_dir.clone();
+    let ids = match matches.subcommand() {
+        (_, Some(m)) => match m.values_of("id") {
+            Some(ids) => ids
+                .map(|id| id.parse())
+                .collect::<std::result::Result<Vec<u64>, _>>()?,
+            None => vec![],
+        },
+        _ => vec![],
+    };
+    let (event, label, n) = match matches.subcommand() {
+        ("add", Some(m)) => (
+            "queued",
+            "Queued",
+            process_enqueue(session, m.value_of("url").unwrap(), rule)?,
+        ),
+        ("list", Some(_)) => {
+            print_queue(&Queue::load(&data_dir)?.jobs);
+            return Ok(());
+        }
+        ("retry", Some(_)) => (
+            "retried",
+            "Retried",
+            Queue::update(&data_dir, |q| q.retry(&ids))?,
+        ),
+        ("cancel", Some(_)) => (
+            "cancelled",
+            "Cancelled",
+            Queue::update(&data_dir, |q| q.cancel(&ids))?,
+        ),
+        ("clear", Some(_)) => (
+            "cleared",
+            "Cleared",
+            Queue::update(&data_dir, |q| q.clear())?,
+        ),
+        ("run", Some(_)) => {
+            let recovered = Queue::update(&data_dir, |q| q.recover())?;
+            if recovered > 0 {
+                report("recovered", json!({ "jobs": recovered }), || {
+                    println!("Recovered: {} interrupted jobs", recovered)
+                });
+            }
+            return run_queue(session, None);
+        }
+        _ => return Ok(()),
+    };
+    report(event, json!({ "jobs": n }), || {
+        println!("{}: {} jobs", label, n)
+    });
+    Ok(())
+}
+
+/// 将链接对应的章节加入队列，返回加入的数量
+fn process_enqueue(session: &Session, url: &str, rule: Option<&str>) -> Result<usize> {
+    match tasks::resolve(url)? {
+        (extractor, Target::Comic(mut comic)) => {
+            let selects = select_comic_chapters(session, extractor, &mut comic, rule)?;
+            Ok(enqueue_chapters(&comic, &selects, true)?.len())
+        }
+        (_, Target::Chapter(chapter)) => {
+            if skip_downloaded(&chapter)? {
+                return Ok(0);
+            }
+            let config = CONFIG.lock().unwrap().clone();
+            Queue::update(&config.data_dir, |queue| {
+                queue.push(
+                    url,
+                    "",
+                    &Origin::default(),
+                    true,
+                    config.format.clone(),
+                    None,
+                )
+            })?;
+            Ok(1)
+        }
+    }
+}
+
+fn print_queue(jobs: &[Job]) {
+    report("queue", json!({ "jobs": jobs }), || {
+        for job in jobs {
+            println!(
+                "{}\t{}\t{}\t{}",
+                job.id,
+                job.state.as_str(),
+                job.format.as_deref().unwrap_or("-"),
+                job_name(job)
+            );
+            if let Some(e) = &job.error {
+                println!("\t{}", e);
+            }
+        }
+    });
+}
+
+fn process_save(
+    session: &Session,
+    extractor: &ExtractorObject,
+    chapter: &mut Chapter,
+    origin: &Origin,
+) -> Result<String> {
+    let config = CONFIG.lock().unwrap().clone();
+    let mut progress = CliProgress::new(&chapter.url);
+    let saved = tasks::save_chapter(session, &config, extractor, chapter, origin, &mut progress);
+    progress.finish();
+    let saved = saved?;
+    report_resumed(saved.cached);
+    saved.check()?;
+    Ok(saved.base_dir)
+}
+
+fn report_resumed(cached: usize) {
+    if cached > 0 {
+        report("resumed", json!({ "cached": cached }), || {
+            println!("Resumed: {} pages loaded from cache", cached)
+        });
+    }
+}
+
+/// 命令行的下载进度：获取页面列表时显示动画，下载时显示进度条，JSON 模式下输出事件
+struct CliProgress {
+    url: String,
+    spinner: ProgressBar,
+    bar: ProgressBar,
+}
+
+impl CliProgress {
+    fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            spinner: create_spinner("Fetching..."),
+            bar: ProgressBar::hidden(),
+        }
+    }
+
+    fn finish(&self) {
+        self.spinner.finish_and_clear();
+        self.bar.finish_and_clear();
+    }
+}
+
+impl Drop for CliProgress {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl Progress for CliProgress {
+    fn start(&mut self, title: &str, base_dir: &str, total: usize) {
+        self.spinner.finish_and_clear();
+        if output::is_json() {
+            output::emit(
+                "download",
+                json!({ "title": title, "url": self.url, "cache": base_dir, "pages": total }),
+            );
+        } else {
+            self.bar = ProgressBar::new(total as u64);
+        }
+    }
+
+    fn page(&mut self, index: usize, page: &Page, result: &Result<bool>) {
+        if let (false, Err(e)) = (output::is_json(), result) {
+            self.bar.println(format!(
+                "Failed: page {} ({}): {}",
+                index + 1,
+                page.address,
+                e
+            ));
+        }
+        if output::is_json() {
+            output::emit(
+                "page",
+                json!({
+                    "index": index,
+                    "fname": page.fname,
+                    "cached": result.as_ref().ok().cloned().unwrap_or(false),
+                    "error": result.as_ref().err().map(|e| e.to_string()),
+                }),
+            );
+        } else {
+            self.bar.inc(1);
+        }
+    }
+}
+
+/// 下载历史中已有的章节：设置了 skip-downloaded 时跳过，否则提示后重新下载
+fn skip_downloaded(chapter: &Chapter) -> Result<bool> {
+    let config = CONFIG.lock().unwrap().clone();
+    let entry = match History::open(&config.data_dir).find(&chapter.url)? {
+        Some(entry) => entry,
+        None => return Ok(false),
+    };
+    let data =
+        json!({ "title": entry.title, "url": entry.chapter_url, "downloaded_at": entry.timestamp });
+    if config.skip_downloaded {
+        report("skipped", data, || {
+            println!(
+                "Skipped: {} (downloaded at {})",
+                entry.title, entry.timestamp
+            )
+        });
+        return Ok(true);
+    }
+    report("warning", data, || {
+        eprintln!(
+            "Warning: {} was already downloaded at {}",
+            entry.title, entry.timestamp
+        )
+    });
+    Ok(false)
+}
+
+fn process_history(matches: &clap::ArgMatches) -> Result<()> {
+    let data_dir = CONFIG.lock().unwrap().data_dir.clone();
+    let history = History::open(&data_dir);
+    match matches.subcommand() {
+        ("list", Some(m)) => {
+            let entries = history.entries()?;
+            let skip = match m.value_of("limit") {
+                Some(limit) => entries.len().saturating_sub(limit.parse()?),
+                None => 0,
+            };
+            print_history(&entries[skip..]);
+        }
+        ("search", Some(m)) => print_history(&history.search(m.value_of("keywords").unwrap())?),
+        ("prune", Some(m)) => {
+            let keep = match m.value_of("keep") {
+                Some(keep) => Some(keep.parse::<usize>()?),
+                None => None,
+            };
+            let before = match m.value_of("older-than") {
+                Some(days) => Some(chrono::Utc::now() - chrono::Duration::days(days.parse()?)),
+                None => None,
+            };
+            let pruned = history.prune_older(keep, before)?;
+            report("pruned", json!({ "pruned": pruned }), || {
+                println!("Pruned: {} entries", pruned)
+            });
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn print_history(entries: &[history::Entry]) {
+    report("history", json!({ "entries": entries }), || {
+        for entry in entries {
+            println!(
+                "{}\t{}\t{}\t{}",
+                entry.timestamp,
+                entry.format.as_deref().unwrap_or("-"),
+                entry.title,
+                entry.output.as_ref().unwrap_or(&entry.chapter_url)
+            );
+        }
+    });
+}
+
+fn process_follow(session: &Session, url: &str, skip_existing: bool) -> Result<()> {
+    let extractor = match extractors::domain_route(url) {
+        Some(DomainRoute::Comic(domain)) => get_exrt(domain)?,
+        _ => return Err(err_msg("Only comic home pages can be followed")),
+    };
+    let mut comic = Comic::new("", url);
     let spinner = create_spinner("Fetching...");
-    let pages_iter = extractor.pages_iter(chapter)?;
+    throttle(session, &platform(url));
+    extractor.fetch_chapters(&mut comic)?;
     spinner.finish_and_clear();
-    let base_dir = pages_iter.chapter_title_clone();
-    let bar = ProgressBar::new(pages_iter.total as u64);
-    let mut pages = vec![];
-    for page in pages_iter {
-        let mut page = page?;
-        let mut resp = get_resp(&page.address, &page_headers)?;
-        let mut buf = vec![];
-        resp.copy_to(&mut buf)?;
-        cache_to(&base_dir, &page.fname, &buf)?;
-        if let Some(mime) = resp.headers().get(reqwest::header::CONTENT_TYPE) {
-            page.fmime = mime.to_str()?.to_string();
-        }
-        pages.push(page);
-        bar.inc(1);
-    }
-    bar.finish_and_clear();
-    chapter.pages = pages;
-    let metadata = serde_json::to_string(chapter)?;
-    cache_to(&base_dir, "metadata.json", &metadata.as_bytes().to_vec())?;
-    let exporter = exporters::gen_expo(
-        CONFIG
-            .lock()
-            .unwrap()
-            .get("format")
-            .unwrap_or(&"none".to_string()),
-        &base_dir,
-    )?;
-    let spinner = create_spinner("Saving...");
-    let path = exporter.expo()?;
+    let data_dir = CONFIG.lock().unwrap().data_dir.clone();
+    let mut library = Library::load(&data_dir)?;
+    if !library.follow(&comic, skip_existing) {
+        return Err(err_msg(format!("Already followed: {}", comic.title)));
+    }
+    library.save(&data_dir)?;
+    let history = History::open(&data_dir).entries()?;
+    let new_chapters = library
+        .get(url)
+        .unwrap()
+        .new_chapters(&comic, &history)
+        .len();
+    report(
+        "followed",
+        json!({ "title": comic.title, "url": comic.url, "chapters": comic.chapters.len(), "new": new_chapters }),
+        || {
+            println!(
+                "Followed: {} ({} chapters, {} new)",
+                comic.title,
+                comic.chapters.len(),
+                new_chapters
+            )
+        },
+    );
+    Ok(())
+}
+
+fn process_unfollow(url: &str) -> Result<()> {
+    let data_dir = CONFIG.lock().unwrap().data_dir.clone();
+    let mut library = Library::load(&data_dir)?;
+    if !library.unfollow(url) {
+        return Err(err_msg(format!("Not followed: {}", url)));
+    }
+    library.save(&data_dir)?;
+    report("unfollowed", json!({ "url": url }), || {
+        println!("Unfollowed: {}", url)
+    });
+    Ok(())
+}
+
+/// 获取订阅漫画的最新章节列表，返回漫画及其新章节的序号
+fn fetch_followed(session: &Session) -> Result<Vec<(Comic, Vec<usize>)>> {
+    let config = CONFIG.lock().unwrap().clone();
+    let spinner = create_spinner("Checking...");
+    let checked = tasks::check_followed(session, &config);
     spinner.finish_and_clear();
-    println!("Succeed: {}", path.to_str().unwrap_or(&chapter.title));
+    let mut updates = vec![];
+    for (comic, new_chapters) in checked? {
+        match new_chapters {
+            Ok(new_chapters) => updates.push((comic, new_chapters)),
+            // 单个漫画检查失败不影响其它漫画
+            Err(e) => report(
+                "error",
+                json!({ "url": comic.url, "message": e.to_string() }),
+                || eprintln!("Failed: {} ({}): {}", comic.title, comic.url, e),
+            ),
+        }
+    }
+    Ok(updates)
+}
+
+fn process_check(session: &Session) -> Result<()> {
+    for (comic, new_chapters) in fetch_followed(session)? {
+        let chapters = new_chapters
+            .iter()
+            .map(|n| {
+                let chapter = &comic.chapters[n - 1];
+                json!({ "index": n, "title": chapter.title, "url": chapter.url })
+            })
+            .collect::<Vec<_>>();
+        report(
+            "new_chapters",
+            json!({ "title": comic.title, "url": comic.url, "chapters": chapters }),
+            || {
+                println!("{}: {} new", comic.title, new_chapters.len());
+                for n in &new_chapters {
+                    println!("  {}. {}", n, comic.chapters[n - 1].title);
+                }
+            },
+        );
+    }
+    Ok(())
+}
+
+/// 新章节加入队列后依次下载并导出
+fn process_update(session: &Session) -> Result<()> {
+    // 每个新章节单独导出，不合并为卷
+    let config = Settings {
+        volume: None,
+        ..CONFIG.lock().unwrap().clone()
+    };
+    let mut ids = vec![];
+    for (comic, new_chapters) in fetch_followed(session)? {
+        if new_chapters.is_empty() {
+            continue;
+        }
+        report(
+            "updating",
+            json!({ "title": comic.title, "url": comic.url, "new": new_chapters.len() }),
+            || println!("{}: {} new", comic.title, new_chapters.len()),
+        );
+        ids.extend(tasks::enqueue_chapters(
+            &config,
+            &comic,
+            &new_chapters,
+            true,
+        )?);
+    }
+    if ids.is_empty() {
+        return Ok(());
+    }
+    run_queue(session, Some(&ids))
+}
+
+fn process_serve(session: &Session, addr: &str, allow_origin: Option<&str>) -> Result<()> {
+    let config = CONFIG.lock().unwrap().clone();
+    report("listening", json!({ "address": addr }), || {
+        println!("Listening on http://{}", addr)
+    });
+    server::serve(addr, allow_origin, session.clone(), config)
+}
+
+fn process_cached() -> Result<()> {
+    let cache_dir = CONFIG.lock().unwrap().cache_dir.clone();
+    let cached = exporters::cached_chapters(&cache_dir)?;
+    let chapters = cached
+        .iter()
+        .map(|(base_dir, metadata)| {
+            json!({
+                "cache": base_dir,
+                "title": metadata.chapter.title,
+                "url": metadata.chapter.url,
+                "pages": metadata.total(),
+                "pending": metadata.pending,
+            })
+        })
+        .collect::<Vec<_>>();
+    report("cached", json!({ "chapters": chapters }), || {
+        for (base_dir, metadata) in &cached {
+            let status = if metadata.pending.is_empty() {
+                format!("{} pages", metadata.chapter.pages.len())
+            } else {
+                format!(
+                    "{}/{} pages, incomplete",
+                    metadata.finished.len(),
+                    metadata.total()
+                )
+            };
+            println!("{}\t{}\t{}", base_dir, metadata.chapter.title, status);
+        }
+    });
     Ok(())
 }
+
+fn process_migrate_cache() -> Result<()> {
+    let cache_dir = CONFIG.lock().unwrap().cache_dir.clone();
+    let migrated = migrate_cache(&cache_dir)?;
+    let moves = migrated
+        .iter()
+        .map(|(from, to)| json!({ "from": from.display().to_string(), "to": to }))
+        .collect::<Vec<_>>();
+    report("migrated", json!({ "chapters": moves }), || {
+        for (from, to) in &migrated {
+            println!("Migrated: {} -> {}", from.display(), to);
+        }
+        if migrated.is_empty() {
+            println!("No legacy caches found");
+        }
+    });
+    Ok(())
+}
+
+fn report_cached(base_dir: &str) {
+    let cache_dir = CONFIG.lock().unwrap().cache_dir.clone();
+    report(
+        "saved",
+        json!({ "cache": base_dir, "path": cache_dir.join(base_dir).display().to_string() }),
+        || println!("Cached: {}", base_dir),
+    );
+}
+
+fn process_export_cached(base_dirs: &[&str]) -> Result<()> {
+    if CONFIG.lock().unwrap().volume.is_some() {
+        return process_export(base_dirs);
+    }
+    for base_dir in base_dirs {
+        process_export(&[*base_dir])?;
+    }
+    Ok(())
+}
+
+fn process_export(base_dirs: &[&str]) -> Result<()> {
+    let config = CONFIG.lock().unwrap().clone();
+    export_with(&config, base_dirs)
+}
+
+fn export_with(config: &Settings, base_dirs: &[&str]) -> Result<()> {
+    for format in tasks::formats(config) {
+        let spinner = create_spinner("Saving...");
+        let path = tasks::export_chapters(config, base_dirs, &format);
+        spinner.finish_and_clear();
+        report_exported(&format, &path?);
+    }
+    Ok(())
+}
+
+fn report_exported(format: &str, path: &Path) {
+    let size = history::path_size(path);
+    report(
+        "exported",
+        json!({ "format": format, "path": path.display().to_string(), "size": size }),
+        || println!("Succeed: {}", path.display()),
+    );
+}
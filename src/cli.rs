@@ -1,5 +1,5 @@
 use crate::VERSION;
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 
 const AUTHOR: &'static str = "Hentioe (绅士喵), <me@bluerain.io>";
 
@@ -8,18 +8,341 @@ pub fn build_cli<'a, 'b>() -> App<'a, 'b> {
         .version(VERSION)
         .about("A tool for exporting online comics")
         .author(AUTHOR)
+        .setting(AppSettings::VersionlessSubcommands)
         .arg(
             Arg::with_name("url")
                 .help("The address of the comic home page or reading page")
                 .takes_value(true)
                 .required(false),
         )
+        .arg(input_arg().conflicts_with("url"))
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help("Path to the config file (default: ~/.config/mikack-cli/config.toml)")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
         .arg(
             Arg::with_name("save-format")
                 .long("format")
                 .short("f")
-                .help("Saved format (eg: epub)")
+                .help("Saved format, multiple formats separated by commas (eg: epub,cbz)")
                 .takes_value(true)
-                .required(false),
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("chapters")
+                .long("chapters")
+                .short("c")
+                .help("Select chapters without prompting (eg: 1-10,^5 or all, latest, last:3)")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("volume")
+                .long("volume")
+                .help("Merge the selected chapters into a single volume with this name")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("output-dir")
+                .long("output-dir")
+                .short("o")
+                .help("Directory for exported files (default: ~/Downloads/mikack-cli)")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("cache-dir")
+                .long("cache-dir")
+                .help("Directory for downloaded pages (default: ~/.cache/mikack-cli)")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .long("jobs")
+                .short("j")
+                .help("Number of pages to download concurrently (default: 4)")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("max-attempts")
+                .long("max-attempts")
+                .help("Maximum number of attempts for each page (default: 5)")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .help("Idle timeout in seconds while waiting for response data (default: 30)")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("connect-timeout")
+                .long("connect-timeout")
+                .help("Connect timeout in seconds (default: same as --timeout)")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("rate-limit")
+                .long("rate-limit")
+                .help("Maximum requests per second to each host (eg: 0.5)")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("user-agent")
+                .long("user-agent")
+                .help("Custom User-Agent header")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .long("proxy")
+                .help("Proxy address (eg: http://127.0.0.1:8080, socks5://127.0.0.1:1080)")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("insecure")
+                .long("insecure")
+                .help("Accept invalid certificates (dangerous)")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("rtl")
+                .long("rtl")
+                .help("Read from right to left (eg: Japanese manga)")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print JSON lines instead of text, without spinners and prompts")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("skip-downloaded")
+                .long("skip-downloaded")
+                .help("Skip chapters found in the download history")
+                .global(true),
+        )
+        .subcommand(SubCommand::with_name("platforms").about("List supported platforms"))
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Print comic and chapter metadata")
+                .arg(url_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("download")
+                .about("Download chapters into the cache without exporting")
+                .arg(url_arg().required_unless("input"))
+                .arg(input_arg().conflicts_with("url")),
+        )
+        .subcommand(SubCommand::with_name("cached").about("List cached chapters"))
+        .subcommand(
+            SubCommand::with_name("migrate-cache")
+                .about("Move caches of older versions into the current layout"),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export cached chapters without network access")
+                .arg(
+                    Arg::with_name("cached-chapter")
+                        .help("The cache path of the chapter, eg: www.dm5.com/m136026/m1029843 (see `cached`)")
+                        .multiple(true)
+                        .required_unless("all"),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .short("a")
+                        .help("Export all complete cached chapters"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("follow")
+                .about("Follow a comic to check for new chapters")
+                .arg(
+                    Arg::with_name("url")
+                        .help("The address of the comic home page")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("skip-existing")
+                        .long("skip-existing")
+                        .help("Only treat chapters released from now on as new"),
+                ),
         )
+        .subcommand(
+            SubCommand::with_name("unfollow")
+                .about("Stop following a comic")
+                .arg(
+                    Arg::with_name("url")
+                        .help("The address of the comic home page")
+                        .required(true),
+                ),
+        )
+        .subcommand(SubCommand::with_name("check").about("List new chapters of followed comics"))
+        .subcommand(
+            SubCommand::with_name("update")
+                .about("Download and export new chapters of followed comics"),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("List, search and prune the download history")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List download history")
+                        .arg(
+                            Arg::with_name("limit")
+                                .long("limit")
+                                .short("n")
+                                .help("Only list the latest n entries")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("search")
+                        .about("Search download history by title or URL")
+                        .arg(
+                            Arg::with_name("keywords")
+                                .help("Search keywords")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("prune")
+                        .about("Remove old entries from download history")
+                        .arg(
+                            Arg::with_name("older-than")
+                                .long("older-than")
+                                .help("Remove entries older than this many days")
+                                .takes_value(true)
+                                .required_unless("keep"),
+                        )
+                        .arg(
+                            Arg::with_name("keep")
+                                .long("keep")
+                                .help("Keep only the latest n entries")
+                                .takes_value(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("queue")
+                .about("Manage the persistent download queue")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Add chapters to the queue without downloading")
+                        .arg(url_arg()),
+                )
+                .subcommand(SubCommand::with_name("list").about("List queued jobs"))
+                .subcommand(
+                    SubCommand::with_name("retry")
+                        .about("Retry failed (or the given) jobs")
+                        .arg(job_ids_arg().required(false)),
+                )
+                .subcommand(
+                    SubCommand::with_name("cancel")
+                        .about("Cancel pending or failed jobs")
+                        .arg(job_ids_arg().required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("clear").about("Remove done and cancelled jobs"),
+                )
+                .subcommand(
+                    SubCommand::with_name("run")
+                        .about("Run pending jobs, resuming interrupted ones"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Run a local HTTP API and OPDS catalog for downloads and exported books")
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .short("l")
+                        .help("Address to listen on (default: 127.0.0.1:8080)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("allow-origin")
+                        .long("allow-origin")
+                        .help("Web page origin allowed to call the API from a browser (eg: http://localhost:3000)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("search")
+                .about("Search comics on a platform")
+                .arg(platform_arg())
+                .arg(
+                    Arg::with_name("keywords")
+                        .help("Search keywords")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("Browse the comic index of a platform")
+                .arg(platform_arg())
+                .arg(
+                    Arg::with_name("page")
+                        .help("Page number (default: 1)")
+                        .required(false),
+                ),
+        )
+}
+
+fn url_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("url")
+        .help("The address of the comic home page or reading page")
+        .required(true)
+}
+
+/// 仅用于顶层命令和 download 子命令
+fn input_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("input")
+        .long("input")
+        .short("i")
+        .help(
+            "Read URLs from a file (or `-` for stdin), one per line with an optional chapter rule",
+        )
+        .takes_value(true)
+}
+
+fn platform_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("platform")
+        .help("The domain of the platform (see `platforms`)")
+        .required(true)
+}
+
+fn job_ids_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("id")
+        .help("Job IDs (see `queue list`)")
+        .multiple(true)
 }
@@ -0,0 +1,480 @@
+use crate::ratelimit::{parse_retry_after, RateLimit, RateLimiter};
+use crate::{cache_to, cached_page, fsname, platform};
+use indicatif::ProgressBar;
+use mikack::{error::*, models::Page};
+use rand::Rng;
+use reqwest::{
+    blocking::Client,
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, RETRY_AFTER},
+    Proxy, StatusCode,
+};
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// 失败请求的重试策略（指数退避 + 随机抖动）
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// 最大尝试次数（包括第一次请求）
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// 第 attempt 次失败后的等待时间，在退避时长的一半到全部之间随机取值
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = (self.base_delay.as_millis() as u64)
+            .saturating_mul(2u64.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay.as_millis() as u64);
+        let half = backoff / 2;
+        Duration::from_millis(half + rand::thread_rng().gen_range(0, half + 1))
+    }
+}
+
+/// 单次请求失败的类型
+enum Attempt {
+    /// 网络错误、5xx 响应或不完整的响应体，可以重试
+    Transient(Error),
+    /// 429 或带 Retry-After 的响应，放慢速度后重试
+    Throttled(Error, Option<Duration>),
+    Fatal(Error),
+}
+
+/// 下载失败的页面
+pub struct Failure {
+    /// 页面在章节中的位置（从 0 开始）
+    pub index: usize,
+    pub error: String,
+}
+
+/// 一个章节的下载结果
+pub struct Download {
+    /// 章节的全部页面（包括下载失败的页面），保持原有顺序
+    pub pages: Vec<Page>,
+    pub failures: Vec<Failure>,
+    /// 直接使用缓存（未重新下载）的页面数量
+    pub cached: usize,
+}
+
+impl Download {
+    /// 尚未下载完成的页码（从 1 开始）
+    pub fn pending(&self) -> Vec<usize> {
+        self.failures
+            .iter()
+            .map(|failure| failure.index + 1)
+            .collect()
+    }
+}
+
+/// 章节下载的进度回调
+pub trait Progress {
+    /// 获取到页面列表后调用
+    fn start(&mut self, _title: &str, _base_dir: &str, _total: usize) {}
+    /// 每个页面完成（下载成功、使用缓存或失败）后调用，result 为是否使用了缓存
+    fn page(&mut self, _index: usize, _page: &Page, _result: &Result<bool>) {}
+}
+
+impl Progress for ProgressBar {
+    fn start(&mut self, _title: &str, _base_dir: &str, total: usize) {
+        self.set_length(total as u64);
+    }
+
+    fn page(&mut self, _index: usize, _page: &Page, _result: &Result<bool>) {
+        self.inc(1);
+    }
+}
+
+/// 章节的下载选项
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// 并发下载的页面数量
+    pub jobs: usize,
+    /// 下载页面时附加的请求头
+    pub headers: HashMap<String, String>,
+    /// 图片请求的限速
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// HTTP 客户端选项
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    /// 建立连接的超时，未指定时与读取超时相同
+    pub connect_timeout: Option<Duration>,
+    /// 等待响应头及每次读取响应体的空闲超时，持续收到数据的大文件不受限制
+    pub read_timeout: Option<Duration>,
+    pub user_agent: Option<String>,
+    /// 代理地址（eg: http://127.0.0.1:8080, socks5://127.0.0.1:1080）
+    pub proxy: Option<String>,
+    /// 接受无效的证书（默认校验）
+    pub accept_invalid_certs: bool,
+    pub retry: RetryPolicy,
+}
+
+/// 下载会话，所有请求共用同一个 HTTP 客户端（连接池、keep-alive、TLS 会话复用）
+/// 及按主机名限速的状态
+#[derive(Clone)]
+pub struct Session {
+    client: Client,
+    read_timeout: Option<Duration>,
+    retry: RetryPolicy,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Session {
+    pub fn new(options: &ClientOptions) -> Result<Self> {
+        // 不设置整个请求的总超时，读取超时在每个请求上单独设置
+        let mut builder = Client::builder()
+            .danger_accept_invalid_certs(options.accept_invalid_certs)
+            .timeout(None);
+        if let Some(timeout) = options.connect_timeout.or(options.read_timeout) {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(user_agent) = &options.user_agent {
+            builder = builder.user_agent(user_agent.as_str());
+        }
+        if let Some(proxy) = &options.proxy {
+            builder = builder.proxy(Proxy::all(proxy.as_str())?);
+        }
+        Ok(Self {
+            client: builder.build()?,
+            read_timeout: options.read_timeout,
+            retry: options.retry.clone(),
+            limiter: Arc::new(RateLimiter::default()),
+        })
+    }
+
+    /// 向 host 发出请求前按限速等待，同一会话的所有线程共享限速状态
+    pub fn throttle(&self, host: &str, limit: Option<&RateLimit>) {
+        self.limiter.wait(host, limit);
+    }
+
+    /// 按限速读取完整的响应体及其 Content-Type，可重试的错误按重试策略重试。
+    /// 被限流时放慢对该主机的请求
+    pub fn fetch(
+        &self,
+        url: &str,
+        headers: &HashMap<String, String>,
+        limit: Option<&RateLimit>,
+    ) -> Result<(Vec<u8>, Option<String>)> {
+        let host = platform(url);
+        let mut attempt = 1;
+        loop {
+            self.throttle(&host, limit);
+            let error = match self.try_fetch(url, headers) {
+                Ok(fetched) => {
+                    self.limiter.speed_up(&host);
+                    return Ok(fetched);
+                }
+                Err(Attempt::Fatal(e)) => return Err(e),
+                Err(Attempt::Transient(e)) => e,
+                // 等待时间由限速状态决定
+                Err(Attempt::Throttled(e, retry_after)) => {
+                    self.limiter.slow_down(&host, retry_after);
+                    if attempt >= self.retry.max_attempts {
+                        return Err(give_up(e, attempt));
+                    }
+                    attempt += 1;
+                    continue;
+                }
+            };
+            if attempt >= self.retry.max_attempts {
+                return Err(give_up(error, attempt));
+            }
+            thread::sleep(self.retry.delay(attempt));
+            attempt += 1;
+        }
+    }
+
+    fn try_fetch(
+        &self,
+        url: &str,
+        headers: &HashMap<String, String>,
+    ) -> std::result::Result<(Vec<u8>, Option<String>), Attempt> {
+        let header_map = header_map(headers).map_err(Attempt::Fatal)?;
+        let mut request = self.client.get(url).headers(header_map);
+        // 阻塞客户端将请求的超时分别作用于等待响应头和响应体的每一次读取
+        if let Some(timeout) = self.read_timeout {
+            request = request.timeout(timeout);
+        }
+        let resp = request.send().map_err(|e| {
+            if e.is_builder() {
+                Attempt::Fatal(e.into())
+            } else {
+                Attempt::Transient(e.into())
+            }
+        })?;
+        let status = resp.status();
+        let retry_after = resp
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        if status == StatusCode::TOO_MANY_REQUESTS
+            || (status.is_server_error() && retry_after.is_some())
+        {
+            return Err(Attempt::Throttled(
+                err_msg(format!("HTTP {}", status)),
+                retry_after,
+            ));
+        }
+        if status.is_server_error() {
+            return Err(Attempt::Transient(err_msg(format!("HTTP {}", status))));
+        }
+        if !status.is_success() {
+            return Err(Attempt::Fatal(err_msg(format!("HTTP {}", status))));
+        }
+        let mime = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|mime| mime.to_str().ok())
+            .map(|mime| mime.to_string());
+        let expected_len = resp.content_length();
+        Ok((read_body(resp, expected_len)?, mime))
+    }
+
+    /// 以 jobs 个线程并发下载页面并写入缓存，单个页面失败不影响其它页面。
+    /// 缓存中已存在且有效的页面不会重新下载
+    pub fn download_pages<I>(
+        &self,
+        pages: I,
+        cache_dir: &Path,
+        base_dir: &str,
+        options: &DownloadOptions,
+        progress: &mut dyn Progress,
+    ) -> Result<Download>
+    where
+        I: Iterator<Item = Result<Page>>,
+    {
+        let (task_tx, task_rx) = mpsc::channel::<(usize, Page)>();
+        let task_rx = Arc::new(Mutex::new(task_rx));
+        let (done_tx, done_rx) = mpsc::channel::<(usize, Page, Result<bool>)>();
+        let mut workers = vec![];
+        for _ in 0..options.jobs.max(1) {
+            let task_rx = task_rx.clone();
+            let done_tx = done_tx.clone();
+            let cache_dir = cache_dir.to_path_buf();
+            let base_dir = base_dir.to_string();
+            let options = options.clone();
+            let session = self.clone();
+            workers.push(thread::spawn(move || loop {
+                let task = task_rx.lock().unwrap().recv();
+                match task {
+                    Ok((i, mut page)) => {
+                        let result =
+                            download_page(&session, &mut page, &cache_dir, &base_dir, &options);
+                        if done_tx.send((i, page, result)).is_err() {
+                            break;
+                        }
+                    }
+                    // 任务已全部分发
+                    Err(_) => break,
+                }
+            }));
+        }
+        drop(done_tx);
+
+        let mut finished = vec![];
+        let mut failures = vec![];
+        let mut cached = 0;
+        // 页面完成后立即更新进度，不必等到全部页面地址解析完
+        let mut record = |(index, page, result): (usize, Page, Result<bool>)| {
+            progress.page(index, &page, &result);
+            match result {
+                Ok(from_cache) => {
+                    if from_cache {
+                        cached += 1;
+                    }
+                }
+                Err(e) => failures.push(Failure {
+                    index,
+                    error: e.to_string(),
+                }),
+            }
+            finished.push((index, page));
+        };
+
+        // 页面地址仍在当前线程中按顺序解析
+        let mut total = 0;
+        let mut fetch_err = None;
+        // 页面文件名来自远程，清理后在章节内去重（避开 metadata.json）
+        let mut taken = HashSet::new();
+        taken.insert("metadata.json".to_string());
+        for page in pages {
+            match page {
+                Ok(mut page) => {
+                    page.fname =
+                        fsname::unique_file_name(&fsname::sanitize(&page.fname), &mut taken);
+                    task_tx
+                        .send((total, page))
+                        .map_err(|_| err_msg("Download workers exited unexpectedly"))?;
+                    total += 1;
+                }
+                Err(e) => {
+                    fetch_err = Some(e);
+                    break;
+                }
+            }
+            while let Ok(done) = done_rx.try_recv() {
+                record(done);
+            }
+        }
+        drop(task_tx);
+        for done in done_rx {
+            record(done);
+        }
+        failures.sort_by_key(|failure| failure.index);
+        for worker in workers {
+            worker
+                .join()
+                .map_err(|_| err_msg("Download worker panicked"))?;
+        }
+        if let Some(e) = fetch_err {
+            return Err(e);
+        }
+        finished.sort_by_key(|(index, _)| *index);
+        Ok(Download {
+            pages: finished.into_iter().map(|(_, page)| page).collect(),
+            failures,
+            cached,
+        })
+    }
+}
+
+/// 下载单个页面，返回是否直接使用了缓存
+fn download_page(
+    session: &Session,
+    page: &mut Page,
+    cache_dir: &Path,
+    base_dir: &str,
+    options: &DownloadOptions,
+) -> Result<bool> {
+    if let Some(mime) = cached_page(cache_dir, base_dir, &page.fname) {
+        page.fmime = mime.to_string();
+        return Ok(true);
+    }
+    let (buf, mime) =
+        session.fetch(&page.address, &options.headers, options.rate_limit.as_ref())?;
+    cache_to(cache_dir, base_dir, &page.fname, &buf)?;
+    if let Some(mime) = mime {
+        page.fmime = mime;
+    }
+    Ok(false)
+}
+
+/// 分块读取响应体，读取超时或长度少于 Content-Length 时可以重试
+fn read_body<R: Read>(
+    mut reader: R,
+    expected_len: Option<u64>,
+) -> std::result::Result<Vec<u8>, Attempt> {
+    let mut buf = vec![];
+    let mut chunk = [0; 16 * 1024];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Attempt::Transient(e.into())),
+        }
+    }
+    if let Some(len) = expected_len {
+        if (buf.len() as u64) < len {
+            return Err(Attempt::Transient(err_msg(format!(
+                "Truncated body: {} of {} bytes",
+                buf.len(),
+                len
+            ))));
+        }
+    }
+    Ok(buf)
+}
+
+fn header_map(headers: &HashMap<String, String>) -> Result<HeaderMap> {
+    let mut header_map = HeaderMap::new();
+    for (key, value) in headers {
+        header_map.insert(
+            HeaderName::from_bytes(key.as_bytes())?,
+            HeaderValue::from_str(&value)?,
+        );
+    }
+    Ok(header_map)
+}
+
+fn give_up(e: Error, attempts: u32) -> Error {
+    err_msg(format!("{} (gave up after {} attempts)", e, attempts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy::default();
+        for _ in 0..100 {
+            let first = policy.delay(1).as_millis();
+            assert!(first >= 250 && first <= 500);
+            let third = policy.delay(3).as_millis();
+            assert!(third >= 1000 && third <= 2000);
+            // 不超过最大等待时间
+            let capped = policy.delay(20).as_millis();
+            assert!(capped >= 15_000 && capped <= 30_000);
+            assert!(policy.delay(u32::max_value()) <= policy.max_delay);
+        }
+        let none = RetryPolicy {
+            base_delay: Duration::from_millis(0),
+            ..RetryPolicy::default()
+        };
+        assert_eq!(none.delay(3), Duration::from_millis(0));
+    }
+
+    /// 先返回数据，之后读取超时
+    struct Stalled(Option<Vec<u8>>);
+
+    impl Read for Stalled {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.take() {
+                Some(data) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                }
+                None => Err(io::Error::new(ErrorKind::TimedOut, "timed out")),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_body() {
+        let body = vec![7; 40 * 1024];
+        assert_eq!(
+            read_body(&body[..], Some(body.len() as u64)).ok(),
+            Some(body.clone())
+        );
+        // 没有 Content-Length 时读取到结束为止
+        assert_eq!(read_body(&body[..], None).ok(), Some(body.clone()));
+        match read_body(&body[..100], Some(body.len() as u64)) {
+            Err(Attempt::Transient(e)) => {
+                assert_eq!(e.to_string(), "Truncated body: 100 of 40960 bytes")
+            }
+            _ => panic!("truncated body accepted"),
+        }
+        match read_body(Stalled(Some(vec![1, 2, 3])), None) {
+            Err(Attempt::Transient(_)) => (),
+            _ => panic!("stalled body accepted"),
+        }
+    }
+}
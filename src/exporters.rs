@@ -1,29 +1,289 @@
-use crate::CACHE_DIR;
+use crate::settings::match_domain;
+use crate::{fsname, platform, Metadata, Origin};
+use chrono::Local;
+use lazy_static::lazy_static;
 use mikack::error::*;
 use mikack::models::Chapter;
+use regex::{Captures, Regex};
 use scan_dir::ScanDir;
-use std::fs::{create_dir_all, File};
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, read_dir, File};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tera::{Context, Tera, Value};
 use zip::{write::FileOptions, ZipWriter};
 
+/// 导出选项
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// 将多个章节合并为一卷时使用的卷名
+    pub volume: Option<String>,
+    /// 从右往左阅读（日漫）
+    pub rtl: bool,
+    pub output_dir: PathBuf,
+    pub cache_dir: PathBuf,
+    /// 导出格式，由 gen_expo 填充
+    pub format: String,
+    /// 输出路径模板（相对于输出目录），未设置时使用 DEFAULT_FILENAME
+    pub filename: Option<String>,
+    /// 各平台（域名）专属的输出路径模板
+    pub platform_filenames: HashMap<String, String>,
+    /// 之前由相同章节导出、可以直接覆盖的输出路径
+    pub replaceable: Vec<PathBuf>,
+}
+
+/// 默认的输出路径模板
+pub const DEFAULT_FILENAME: &'static str = "{volume}.{ext}";
+
 pub trait Exporter {
-    fn from_cache(base_dir: &str) -> Result<Self>
+    fn from_cache(base_dirs: &[&str], options: &Options) -> Result<Self>
     where
         Self: Sized;
     fn expo(&self) -> Result<PathBuf>;
 }
 
-pub fn metadata(base_dir: &str) -> Result<Chapter> {
-    let mut fpath = PathBuf::from(CACHE_DIR);
-    fpath.push(base_dir);
+/// 一次导出的全部章节，单章节导出时卷名即章节标题
+pub struct Volume {
+    pub title: String,
+    pub chapters: Vec<Chapter>,
+    pub origins: Vec<Origin>,
+    pub base_dirs: Vec<String>,
+    pub output_dir: PathBuf,
+    pub cache_dir: PathBuf,
+    format: String,
+    filename: String,
+    replaceable: Vec<PathBuf>,
+}
+
+impl Volume {
+    pub fn from_cache(base_dirs: &[&str], options: &Options) -> Result<Self> {
+        let mut chapters = vec![];
+        let mut origins = vec![];
+        for base_dir in base_dirs {
+            let metadata = metadata(&options.cache_dir, base_dir)?;
+            chapters.push(metadata.chapter);
+            origins.push(metadata.origin);
+        }
+        let title = match (&options.volume, chapters.as_slice()) {
+            (Some(volume), _) => volume.clone(),
+            (None, [chapter]) => chapter.title.clone(),
+            (None, []) => return Err(err_msg("No chapters to export")),
+            (None, _) => return Err(err_msg("Missing volume name for multiple chapters")),
+        };
+        let platform = platform(&chapters[0].url);
+        let filename = match_domain(&options.platform_filenames, &platform)
+            .or(options.filename.as_ref())
+            .cloned()
+            .unwrap_or(DEFAULT_FILENAME.to_string());
+        Ok(Self {
+            title,
+            chapters,
+            origins,
+            base_dirs: base_dirs.iter().map(|d| d.to_string()).collect(),
+            output_dir: options.output_dir.clone(),
+            cache_dir: options.cache_dir.clone(),
+            format: options.format.clone(),
+            filename,
+            replaceable: options.replaceable.clone(),
+        })
+    }
+
+    /// 按模板生成的输出路径，ext 为空时即输出目录。
+    /// 变量的值会被清理，不会产生额外的目录层级。路径已被其它章节的输出占用时以序号区分
+    pub fn output_path(&self, ext: &str) -> Result<PathBuf> {
+        let origin = &self.origins[0];
+        let merged = self.chapters.len() > 1;
+        let chapter = if merged {
+            &self.title
+        } else {
+            &self.chapters[0].title
+        };
+        let mut ctx = Context::new();
+        ctx.insert("volume", &fsname::sanitize(&self.title));
+        ctx.insert(
+            "comic",
+            &fsname::sanitize(origin.comic_title.as_ref().unwrap_or(&self.title)),
+        );
+        ctx.insert("chapter", &fsname::sanitize(chapter));
+        ctx.insert("index", &origin.index.unwrap_or(0));
+        ctx.insert(
+            "platform",
+            &fsname::sanitize(&platform(&self.chapters[0].url)),
+        );
+        ctx.insert("date", &Local::now().format("%Y-%m-%d").to_string());
+        ctx.insert("format", &self.format);
+        ctx.insert("ext", ext);
+        let mut tera = Tera::default();
+        tera.register_filter("pad", pad);
+        tera.add_raw_template("filename", &tera_template(&self.filename))?;
+        let rendered = tera.render("filename", &ctx)?;
+
+        let mut path = self.output_dir.clone();
+        for component in rendered
+            .split(|c| c == '/' || c == '\\')
+            .filter(|c| !c.trim().is_empty())
+        {
+            path.push(fsname::sanitize(component));
+        }
+        if path == self.output_dir {
+            return Err(err_msg(format!(
+                "Output filename template `{}` renders an empty path",
+                self.filename
+            )));
+        }
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+            if path.exists() && !self.replaceable.contains(&path) {
+                let mut taken = read_dir(parent)?
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name().to_string_lossy().to_lowercase())
+                    .collect::<HashSet<_>>();
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let unique = if ext.is_empty() {
+                    fsname::unique_name(&name, &mut taken)
+                } else {
+                    fsname::unique_file_name(&name, &mut taken)
+                };
+                path = parent.join(unique);
+            }
+        }
+        Ok(path)
+    }
+
+    /// 合卷时每个章节的目录名，清理后重名的章节以序号区分
+    pub fn chapter_names(&self) -> Vec<String> {
+        let mut taken = HashSet::new();
+        self.chapters
+            .iter()
+            .map(|chapter| fsname::unique_name(&fsname::sanitize(&chapter.title), &mut taken))
+            .collect()
+    }
+
+    /// 第 i 个章节的页面在输出中的文件名
+    pub fn page_names(&self, i: usize) -> Vec<String> {
+        let mut taken = HashSet::new();
+        self.chapters[i]
+            .pages
+            .iter()
+            .map(|page| fsname::unique_file_name(&fsname::sanitize(&page.fname), &mut taken))
+            .collect()
+    }
+
+    /// 测试用的卷，章节只有标题和链接
+    #[cfg(test)]
+    pub(crate) fn for_test(title: &str, chapters: Vec<Chapter>) -> Self {
+        Self {
+            title: title.to_string(),
+            origins: chapters.iter().map(|_| Origin::default()).collect(),
+            base_dirs: chapters.iter().map(|_| String::new()).collect(),
+            chapters,
+            output_dir: PathBuf::new(),
+            cache_dir: PathBuf::new(),
+            format: String::new(),
+            filename: DEFAULT_FILENAME.to_string(),
+            replaceable: vec![],
+        }
+    }
+
+    /// 第 i 个章节在缓存中的图片路径
+    pub fn cache_path(&self, i: usize, fname: &str) -> PathBuf {
+        let mut path = self.cache_dir.join(&self.base_dirs[i]);
+        path.push(fname);
+        path
+    }
+}
+
+lazy_static! {
+    static ref PLACEHOLDER_RE: Regex = Regex::new(r"\{(\w+)(?::0?(\d+))?\}").unwrap();
+}
+
+/// 将 `{chapter}`、`{index:03}` 形式的模板转换为 Tera 模板，
+/// 本身已是 Tera 语法的模板保持不变
+fn tera_template(template: &str) -> String {
+    if template.contains("{{") || template.contains("{%") {
+        return template.to_string();
+    }
+    PLACEHOLDER_RE
+        .replace_all(template, |caps: &Captures| match caps.get(2) {
+            Some(width) => format!("{{{{ {} | pad(width={}) }}}}", &caps[1], width.as_str()),
+            None => format!("{{{{ {} }}}}", &caps[1]),
+        })
+        .to_string()
+}
+
+/// 模板过滤器：以 0 填充到指定宽度（eg: `{{ index | pad(width=3) }}`）
+fn pad(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let width = args.get("width").and_then(|w| w.as_u64()).unwrap_or(0) as usize;
+    let text = match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    };
+    Ok(Value::String(format!("{:0>width$}", text, width = width)))
+}
+
+/// 读取完整章节的元数据，存在未下载完成的页面时返回错误
+pub fn metadata(cache_dir: &Path, base_dir: &str) -> Result<Metadata> {
+    let metadata = read_metadata(cache_dir, base_dir)?;
+    if !metadata.pending.is_empty() {
+        return Err(err_msg(format!(
+            "Chapter `{}` is incomplete, {} pages pending",
+            metadata.chapter.title,
+            metadata.pending.len()
+        )));
+    }
+    Ok(metadata)
+}
+
+/// 读取缓存中的 metadata.json，不检查章节是否完整
+pub fn read_metadata(cache_dir: &Path, base_dir: &str) -> Result<Metadata> {
+    let mut fpath = cache_dir.join(base_dir);
     create_dir_all(&fpath)?;
     fpath.push("metadata.json");
     let mut file = File::open(fpath)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
-    let chapter = serde_json::from_str::<Chapter>(&contents)?;
-    Ok(chapter)
+    Ok(serde_json::from_str::<Metadata>(&contents)?)
+}
+
+/// 缓存中的全部章节（`<domain>/<comic-id>/<chapter-id>` 及元数据），按路径排序
+pub fn cached_chapters(cache_dir: &Path) -> Result<Vec<(String, Metadata)>> {
+    let mut chapters = vec![];
+    if cache_dir.is_dir() {
+        collect_cached(cache_dir, "", 3, &mut chapters)?;
+    }
+    chapters.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(chapters)
+}
+
+fn collect_cached(
+    cache_dir: &Path,
+    parent: &str,
+    depth: usize,
+    chapters: &mut Vec<(String, Metadata)>,
+) -> Result<()> {
+    for entry in read_dir(cache_dir.join(parent))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let base_dir = if parent.is_empty() {
+            name
+        } else {
+            format!("{}/{}", parent, name)
+        };
+        if entry.path().join("metadata.json").is_file() {
+            if let Ok(metadata) = read_metadata(cache_dir, &base_dir) {
+                chapters.push((base_dir, metadata));
+            }
+        } else if depth > 1 {
+            collect_cached(cache_dir, &base_dir, depth - 1, chapters)?;
+        }
+    }
+    Ok(())
 }
 
 pub fn archive_dir(dir: &str, dst: &str) -> Result<()> {
@@ -37,6 +297,23 @@ pub fn archive_dir(dir: &str, dst: &str) -> Result<()> {
     Ok(())
 }
 
+/// 打包 EPUB，未压缩的 mimetype 必须是第一个文件
+pub fn archive_epub(dir: &str, dst: &str) -> Result<()> {
+    let file = File::create(dst)?;
+    let mut zip_f = ZipWriter::new(file);
+    zip_f.start_file(
+        "mimetype",
+        FileOptions::default().compression_method(zip::CompressionMethod::Stored),
+    )?;
+    zip_f.write_all(b"application/epub+zip")?;
+    let options = FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .unix_permissions(0o755);
+    archive(dir, &mut Vec::new(), &mut zip_f, &options, "")?;
+    zip_f.finish()?;
+    Ok(())
+}
+
 fn archive(
     dir: &str,
     mut buffer: &mut Vec<u8>,
@@ -73,13 +350,72 @@ fn archive(
     Ok(())
 }
 
+pub mod cbz;
 pub mod copy;
 pub mod epub;
+pub mod mobi;
+pub mod pdf;
 
-pub fn gen_expo(format: &str, base_dir: &str) -> Result<Box<dyn Exporter + Send + Sync>> {
+pub fn gen_expo(
+    format: &str,
+    base_dirs: &[&str],
+    options: &Options,
+) -> Result<Box<dyn Exporter + Send + Sync>> {
+    let options = &Options {
+        format: format.to_string(),
+        ..options.clone()
+    };
     match format {
-        "epub" => Ok(Box::new(epub::Epub::from_cache(base_dir)?)),
-        "none" => Ok(Box::new(copy::Copy::from_cache(base_dir)?)),
+        "epub" => Ok(Box::new(epub::Epub::from_cache(base_dirs, options)?)),
+        "epub3" => Ok(Box::new(
+            epub::Epub::from_cache(base_dirs, options)?.fixed_layout(),
+        )),
+        "mobi" => Ok(Box::new(mobi::Mobi::from_cache(base_dirs, options)?)),
+        "pdf" => Ok(Box::new(pdf::Pdf::from_cache(base_dirs, options)?)),
+        "cbz" => Ok(Box::new(cbz::Cbz::from_cache(base_dirs, options)?)),
+        "none" => Ok(Box::new(copy::Copy::from_cache(base_dirs, options)?)),
         _ => Err(err_msg(format!("Unsupported format: `{}`", format))),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, index: usize) -> String {
+        let mut tera = Tera::default();
+        tera.register_filter("pad", pad);
+        tera.add_raw_template("filename", &tera_template(template))
+            .unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("comic", "海贼王");
+        ctx.insert("chapter", "第1话");
+        ctx.insert("index", &index);
+        ctx.insert("ext", "epub");
+        tera.render("filename", &ctx).unwrap()
+    }
+
+    #[test]
+    fn test_tera_template() {
+        assert_eq!(tera_template("{volume}.{ext}"), "{{ volume }}.{{ ext }}");
+        assert_eq!(
+            tera_template("{comic}/{index:03} {chapter}.{ext}"),
+            "{{ comic }}/{{ index | pad(width=3) }} {{ chapter }}.{{ ext }}"
+        );
+        assert_eq!(tera_template("{index:3}"), "{{ index | pad(width=3) }}");
+        // 已是 Tera 语法时保持不变
+        let template = "{{ comic }}/{% if index %}{{ index }}{% endif %}.{{ ext }}";
+        assert_eq!(tera_template(template), template);
+    }
+
+    #[test]
+    fn test_render_filename() {
+        assert_eq!(
+            render("{comic}/{index:03} {chapter}.{ext}", 7),
+            "海贼王/007 第1话.epub"
+        );
+        // 超出宽度时不截断
+        assert_eq!(render("{index:2}", 1234), "1234");
+        assert_eq!(render("{{ chapter }}.{{ ext }}", 1), "第1话.epub");
+    }
+}
@@ -0,0 +1,86 @@
+use super::*;
+use crate::{image_dimensions, xml_syntax_escaped, VERSION};
+use serde_json::json;
+use std::fs::read;
+use std::path::{Path, PathBuf};
+use tera::{Context, Tera};
+
+pub struct Cbz {
+    volume: Volume,
+    rtl: bool,
+}
+
+impl Cbz {
+    fn render_comic_info(&self, pages: &[serde_json::Value]) -> Result<String> {
+        let template = include_str!("../../template/cbz/ComicInfo.xml");
+        let mut ctx = Context::new();
+        ctx.insert("title", &xml_syntax_escaped(&self.volume.title));
+        ctx.insert("url", &xml_syntax_escaped(&self.volume.chapters[0].url));
+        ctx.insert("pages", pages);
+        ctx.insert("rtl", &self.rtl);
+        ctx.insert("version", VERSION);
+        Ok(Tera::one_off(&template, &ctx, false)?)
+    }
+}
+
+impl Exporter for Cbz {
+    fn from_cache(base_dirs: &[&str], options: &Options) -> Result<Self> {
+        let volume = Volume::from_cache(base_dirs, options)?;
+        Ok(Self {
+            volume,
+            rtl: options.rtl,
+        })
+    }
+
+    fn expo(&self) -> Result<PathBuf> {
+        let cbz_file = self.volume.output_path("cbz")?;
+
+        let mut zip_f = ZipWriter::new(File::create(&cbz_file)?);
+        // 图片本身已压缩，直接存储
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let mut pages_info = vec![];
+        let pages = self
+            .volume
+            .chapters
+            .iter()
+            .enumerate()
+            .flat_map(|(ci, chapter)| {
+                chapter
+                    .pages
+                    .iter()
+                    .enumerate()
+                    .map(move |(pi, page)| (ci, pi, chapter, page))
+            });
+        for (i, (ci, pi, chapter, page)) in pages.enumerate() {
+            let bytes = read(self.volume.cache_path(ci, &page.fname))?;
+            // 以序号命名，保证阅读器按顺序排列
+            let name = match Path::new(&page.fname).extension() {
+                Some(ext) => format!("{:04}.{}", i + 1, ext.to_string_lossy()),
+                None => format!("{:04}", i + 1),
+            };
+            zip_f.start_file(name, options)?;
+            zip_f.write_all(&bytes)?;
+            let (width, height) = match image_dimensions(&bytes) {
+                Some((w, h)) => (Some(w), Some(h)),
+                None => (None, None),
+            };
+            // 合卷时以书签标记每个章节的首页
+            let bookmark = if pi == 0 && self.volume.chapters.len() > 1 {
+                Some(xml_syntax_escaped(&chapter.title))
+            } else {
+                None
+            };
+            pages_info.push(json!({
+                "size": bytes.len(),
+                "width": width,
+                "height": height,
+                "bookmark": bookmark,
+            }));
+        }
+        let comic_info = self.render_comic_info(&pages_info)?;
+        zip_f.start_file("ComicInfo.xml", options)?;
+        zip_f.write_all(comic_info.as_bytes())?;
+        zip_f.finish()?;
+        Ok(cbz_file)
+    }
+}
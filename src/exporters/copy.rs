@@ -1,32 +1,33 @@
 use super::*;
-use crate::{CACHE_DIR, OUTPUT_DIR};
-use mikack::models::Chapter;
 use std::fs::{copy, create_dir_all};
 use std::path::PathBuf;
 
 pub struct Copy {
-    chapter: Chapter,
+    volume: Volume,
 }
 
 impl Exporter for Copy {
-    fn from_cache(base_dir: &str) -> Result<Self> {
-        let chapter = metadata(base_dir)?;
-        Ok(Self { chapter: chapter })
+    fn from_cache(base_dirs: &[&str], options: &Options) -> Result<Self> {
+        let volume = Volume::from_cache(base_dirs, options)?;
+        Ok(Self { volume })
     }
 
     fn expo(&self) -> Result<PathBuf> {
-        let mut cache_dir = PathBuf::from(CACHE_DIR);
-        cache_dir.push(&self.chapter.title);
-        let mut output_dir = PathBuf::from(OUTPUT_DIR);
-        output_dir.push(&self.chapter.title);
-        create_dir_all(&output_dir)?;
+        let output_dir = self.volume.output_path("")?;
+        // 合卷时每个章节一个子目录
+        let merged = self.volume.chapters.len() > 1;
+        let chapter_names = self.volume.chapter_names();
+        for (i, chapter) in self.volume.chapters.iter().enumerate() {
+            let mut chapter_dir = output_dir.clone();
+            if merged {
+                chapter_dir.push(&chapter_names[i]);
+            }
+            create_dir_all(&chapter_dir)?;
 
-        for page in &self.chapter.pages {
-            let mut source_file = cache_dir.clone();
-            source_file.push(&page.fname);
-            let mut target_file = output_dir.clone();
-            target_file.push(&page.fname);
-            copy(source_file, target_file)?;
+            for (page, name) in chapter.pages.iter().zip(self.volume.page_names(i)) {
+                let source_file = self.volume.cache_path(i, &page.fname);
+                copy(source_file, chapter_dir.join(name))?;
+            }
         }
         Ok(output_dir)
     }
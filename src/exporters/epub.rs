@@ -1,42 +1,142 @@
 use super::*;
-use crate::{xml_syntax_escaped, CACHE_DIR, OUTPUT_DIR, VERSION};
+use crate::{image_dimensions, xml_syntax_escaped, VERSION};
 use chrono::{offset::Utc, DateTime};
-use mikack::models::Chapter;
-use std::fs::{copy, create_dir_all, remove_dir_all};
-use std::path::PathBuf;
+use serde::Serialize;
+use std::fs::{copy, create_dir_all, read, remove_dir_all};
+use std::path::{Path, PathBuf};
 use tera::{Context, Tera};
 use uuid::Uuid;
 
 pub struct Epub {
     uuid: String,
-    chapter: Chapter,
+    volume: Volume,
     escaped_title: String,
+    sections: Vec<Section>,
+    fixed_layout: bool,
+    rtl: bool,
+}
+
+/// 目录中的一个章节
+#[derive(Serialize)]
+struct Section {
+    id: String,
+    title: String,
+    url: String,
+    items: Vec<Item>,
+}
+
+/// 章节中的一页
+#[derive(Serialize)]
+struct Item {
+    id: String,
+    n: usize,
+    xhtml: String,
+    img: String,
+    fmime: String,
+    order: usize,
+    width: u32,
+    height: u32,
+}
+
+// 无法识别图片尺寸时使用的默认视口
+const DEFAULT_VIEWPORT: (u32, u32) = (800, 1200);
+
+impl Section {
+    fn build(volume: &Volume) -> Result<Vec<Self>> {
+        let mut order = 0;
+        let mut sections = vec![];
+        for (i, chapter) in volume.chapters.iter().enumerate() {
+            let id = format!("c{}", i + 1);
+            let mut items = vec![];
+            let names = volume.page_names(i);
+            for (pi, page) in chapter.pages.iter().enumerate() {
+                order += 1;
+                let bytes = read(volume.cache_path(i, &page.fname))?;
+                let (width, height) = image_dimensions(&bytes).unwrap_or(DEFAULT_VIEWPORT);
+                items.push(Item {
+                    id: format!("{}p{}", id, pi + 1),
+                    n: pi + 1,
+                    xhtml: format!("{}p{}.xhtml", id, pi + 1),
+                    img: format!("{}/{}", id, names[pi]),
+                    fmime: page.fmime.clone(),
+                    order,
+                    width,
+                    height,
+                });
+            }
+            if items.is_empty() {
+                continue;
+            }
+            sections.push(Section {
+                id,
+                title: xml_syntax_escaped(&chapter.title),
+                url: xml_syntax_escaped(&chapter.url),
+                items,
+            });
+        }
+        Ok(sections)
+    }
 }
 
 static REPO_URL: &'static str = "https://github.com/Hentioe/mikack-cli";
 
 impl Epub {
+    /// 生成 EPUB 3 固定版式（pre-paginated）的图书
+    pub fn fixed_layout(mut self) -> Self {
+        self.fixed_layout = true;
+        self
+    }
+
     fn render_start_page(&self) -> Result<String> {
         let template = include_str!("../../template/epub/start.xhtml");
         let mut ctx = Context::new();
-        ctx.insert("chapter", &self.chapter);
+        ctx.insert("sections", &self.sections);
         ctx.insert("title", &self.escaped_title);
         ctx.insert("repo", REPO_URL);
         Ok(Tera::one_off(&template, &ctx, false)?)
     }
 
-    fn render_page(&self, fname: &str) -> Result<String> {
-        let template = include_str!("../../template/epub/p.xhtml");
+    fn render_page(&self, item: &Item) -> Result<String> {
+        let template = if self.fixed_layout {
+            include_str!("../../template/epub3/p.xhtml")
+        } else {
+            include_str!("../../template/epub/p.xhtml")
+        };
         let mut ctx = Context::new();
         ctx.insert("name", &self.escaped_title);
-        ctx.insert("fname", &fname);
+        ctx.insert("fname", &item.img);
+        ctx.insert("item", item);
+        Ok(Tera::one_off(&template, &ctx, false)?)
+    }
+
+    fn render_package_opf(&self) -> Result<String> {
+        let template = include_str!("../../template/epub3/package.opf");
+        let mut ctx = Context::new();
+        ctx.insert("sections", &self.sections);
+        ctx.insert("title", &self.escaped_title);
+        ctx.insert("uuid", &self.uuid);
+        ctx.insert("version", VERSION);
+        ctx.insert("rtl", &self.rtl);
+        let now = DateTime::<Utc>::from(Utc::now());
+        ctx.insert("date_time", &now.to_rfc3339());
+        ctx.insert("modified", &now.format("%Y-%m-%dT%H:%M:%SZ").to_string());
+
+        Ok(Tera::one_off(&template, &ctx, false)?)
+    }
+
+    fn render_nav_xhtml(&self) -> Result<String> {
+        let template = include_str!("../../template/epub3/nav.xhtml");
+        let mut ctx = Context::new();
+        ctx.insert("sections", &self.sections);
+        ctx.insert("title", &self.escaped_title);
+
         Ok(Tera::one_off(&template, &ctx, false)?)
     }
 
     fn render_metadata_opf(&self) -> Result<String> {
         let template = include_str!("../../template/epub/metadata.opf");
         let mut ctx = Context::new();
-        ctx.insert("chapter", &self.chapter);
+        ctx.insert("sections", &self.sections);
         ctx.insert("title", &self.escaped_title);
         ctx.insert("uuid", &self.uuid);
         ctx.insert("version", VERSION);
@@ -46,13 +146,17 @@ impl Epub {
     }
 
     fn render_stylesheet(&self) -> Result<String> {
-        Ok(include_str!("../../template/epub/stylesheet.css").to_string())
+        if self.fixed_layout {
+            Ok(include_str!("../../template/epub3/stylesheet.css").to_string())
+        } else {
+            Ok(include_str!("../../template/epub/stylesheet.css").to_string())
+        }
     }
 
     fn render_toc_ncx(&self) -> Result<String> {
         let template = include_str!("../../template/epub/toc.ncx");
         let mut ctx = Context::new();
-        ctx.insert("chapter", &self.chapter);
+        ctx.insert("sections", &self.sections);
         ctx.insert("title", &self.escaped_title);
         ctx.insert("uuid", &self.uuid);
 
@@ -60,73 +164,93 @@ impl Epub {
     }
 
     fn render_container_xml(&self) -> Result<String> {
-        Ok(include_str!("../../template/epub/container.xml").to_string())
+        let template = include_str!("../../template/epub/container.xml");
+        let mut ctx = Context::new();
+        let opf = if self.fixed_layout {
+            "package.opf"
+        } else {
+            "metadata.opf"
+        };
+        ctx.insert("opf", opf);
+
+        Ok(Tera::one_off(&template, &ctx, false)?)
     }
 }
 
 impl Exporter for Epub {
-    fn from_cache(base_dir: &str) -> Result<Self> {
-        let chapter = metadata(base_dir)?;
+    fn from_cache(base_dirs: &[&str], options: &Options) -> Result<Self> {
+        let volume = Volume::from_cache(base_dirs, options)?;
         Ok(Self {
             uuid: Uuid::new_v4().to_hyphenated().to_string(),
-            escaped_title: xml_syntax_escaped(&chapter.title),
-            chapter,
+            escaped_title: xml_syntax_escaped(&volume.title),
+            sections: Section::build(&volume)?,
+            volume,
+            fixed_layout: false,
+            rtl: options.rtl,
         })
     }
 
     fn expo(&self) -> Result<PathBuf> {
-        let base_dir = &self.chapter.title;
-        // 写入 start.xhtml
-        let start_xhtml = &self.render_start_page()?.as_bytes().to_vec();
-        write_to(base_dir, "start.xhtml", start_xhtml)?;
-        // 写入页面并复制图片
-        for page in &self.chapter.pages {
-            let mut page_img_path = PathBuf::from(CACHE_DIR);
-            page_img_path.push(base_dir);
-            page_img_path.push(&page.fname);
-
-            let mut target_img_page = PathBuf::from(OUTPUT_DIR);
-            target_img_page.push(base_dir);
-            target_img_page.push(&page.fname);
-
-            copy(page_img_path, target_img_page)?;
-
-            let page_xhtml = &self.render_page(&page.fname)?.as_bytes().to_vec();
-            write_to(base_dir, &format!("{}.xhtml", page.n), page_xhtml)?;
+        if self.sections.is_empty() {
+            return Err(err_msg("No pages to export"));
+        }
+        let epub_file = self.volume.output_path("epub")?;
+        // 打包前的临时目录，先清除上次中断时留下的文件
+        let base_dir = epub_file.with_extension("epub.tmp");
+        if base_dir.exists() {
+            remove_dir_all(&base_dir)?;
+        }
+        // 写入页面并复制图片，每个章节的图片位于独立的目录
+        for (i, chapter) in self.volume.chapters.iter().enumerate() {
+            let target_img_dir = base_dir.join(format!("c{}", i + 1));
+            create_dir_all(&target_img_dir)?;
+
+            for (page, name) in chapter.pages.iter().zip(self.volume.page_names(i)) {
+                copy(
+                    self.volume.cache_path(i, &page.fname),
+                    target_img_dir.join(name),
+                )?;
+            }
+        }
+        for section in &self.sections {
+            for item in &section.items {
+                let page_xhtml = &self.render_page(item)?.as_bytes().to_vec();
+                write_to(&base_dir, &item.xhtml, page_xhtml)?;
+            }
+        }
+        if self.fixed_layout {
+            // 写入 package.opf
+            let package_opf = &self.render_package_opf()?.as_bytes().to_vec();
+            write_to(&base_dir, "package.opf", package_opf)?;
+            // 写入 nav.xhtml
+            let nav_xhtml = &self.render_nav_xhtml()?.as_bytes().to_vec();
+            write_to(&base_dir, "nav.xhtml", nav_xhtml)?;
+        } else {
+            // 写入 start.xhtml
+            let start_xhtml = &self.render_start_page()?.as_bytes().to_vec();
+            write_to(&base_dir, "start.xhtml", start_xhtml)?;
+            // 写入 metadata.opf
+            let metadata_opf = &self.render_metadata_opf()?.as_bytes().to_vec();
+            write_to(&base_dir, "metadata.opf", metadata_opf)?;
         }
-        // 写入 metadata.opf
-        let metadata_opf = &self.render_metadata_opf()?.as_bytes().to_vec();
-        write_to(base_dir, "metadata.opf", metadata_opf)?;
-        // 写入 mimetype
-        let mimetype = &"application/epub+zip".as_bytes().to_vec();
-        write_to(base_dir, "mimetype", mimetype)?;
         // 写入 stylesheet.css
         let stylesheet_css = &self.render_stylesheet()?.as_bytes().to_vec();
-        write_to(base_dir, "stylesheet.css", stylesheet_css)?;
+        write_to(&base_dir, "stylesheet.css", stylesheet_css)?;
         // 写入 toc.ncx
         let toc_ncx = &self.render_toc_ncx()?.as_bytes().to_vec();
-        write_to(base_dir, "toc.ncx", toc_ncx)?;
+        write_to(&base_dir, "toc.ncx", toc_ncx)?;
         // 写入 META-INF/container.xml
         let container_xml = &self.render_container_xml()?.as_bytes().to_vec();
-        let mut meta_inf = PathBuf::from(OUTPUT_DIR);
-        meta_inf.push(base_dir);
-        meta_inf.push("META-INF");
-        create_dir_all(&meta_inf)?;
-        write_to(base_dir, "META-INF/container.xml", container_xml)?;
-
-        let mut epub_dir = PathBuf::from(OUTPUT_DIR);
-        epub_dir.push(base_dir);
-        let mut epub_file = PathBuf::from(OUTPUT_DIR);
-        epub_file.push(format!("{}.epub", self.chapter.title));
-        archive_dir(epub_dir.to_str().unwrap(), epub_file.to_str().unwrap())?;
-        remove_dir_all(&epub_dir)?;
+        write_to(&base_dir.join("META-INF"), "container.xml", container_xml)?;
+
+        archive_epub(base_dir.to_str().unwrap(), epub_file.to_str().unwrap())?;
+        remove_dir_all(&base_dir)?;
         Ok(epub_file)
     }
 }
 
-fn write_to(base_dir: &str, name: &str, bytes: &Vec<u8>) -> Result<()> {
-    let mut fpath = PathBuf::from(OUTPUT_DIR);
-    fpath.push(base_dir);
+fn write_to(base_dir: &Path, name: &str, bytes: &Vec<u8>) -> Result<()> {
+    let mut fpath = base_dir.to_path_buf();
     create_dir_all(&fpath)?;
     fpath.push(name);
     let mut file = File::create(fpath)?;
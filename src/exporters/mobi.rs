@@ -0,0 +1,412 @@
+use super::*;
+use crate::{image_dimensions, xml_syntax_escaped};
+use chrono::offset::Utc;
+use std::fs::read;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// 不依赖 kindlegen 直接生成的 MOBI（KF7）文件。
+/// 固定版式的 EXTH 122 只在 KF8 中生效，KF7 阅读器会忽略它
+pub struct Mobi {
+    volume: Volume,
+    rtl: bool,
+}
+
+// 每条文本记录的最大长度
+const TEXT_RECORD_SIZE: usize = 4096;
+const MOBI_HEADER_LEN: u32 = 232;
+const NULL_INDEX: u32 = 0xffff_ffff;
+
+static FLIS_RECORD: &'static [u8] = b"FLIS\0\0\0\x08\0\x41\0\0\0\0\0\0\xff\xff\xff\xff\0\x01\0\x03\0\0\0\x03\0\0\0\x01\xff\xff\xff\xff";
+static EOF_RECORD: &'static [u8] = b"\xe9\x8e\r\n";
+
+impl Mobi {
+    /// 生成正文 HTML，返回内容与每页图片对应的缓存路径
+    fn render_html(&self) -> (Vec<u8>, Vec<PathBuf>) {
+        let mut html =
+            b"<html><head><guide><reference type=\"toc\" title=\"Table of Contents\" filepos="
+                .to_vec();
+        let mut placeholders = vec![];
+        placeholders.push(html.len());
+        html.extend_from_slice(b"0000000000 /></guide></head><body>");
+        // 合卷时生成章节目录，filepos 先占位，稍后回填
+        let toc_pos = html.len();
+        let merged = self.volume.chapters.len() > 1;
+        if merged {
+            html.extend_from_slice(b"<h2>Table of Contents</h2>");
+            for chapter in &self.volume.chapters {
+                html.extend_from_slice(b"<p><a filepos=");
+                placeholders.push(html.len());
+                html.extend_from_slice(b"0000000000>");
+                html.extend_from_slice(&ascii_html(&chapter.title));
+                html.extend_from_slice(b"</a></p>");
+            }
+            html.extend_from_slice(b"<mbp:pagebreak/>");
+        }
+        let mut targets = vec![toc_pos];
+        let mut images = vec![];
+        for (i, chapter) in self.volume.chapters.iter().enumerate() {
+            targets.push(html.len());
+            for page in &chapter.pages {
+                images.push(self.volume.cache_path(i, &page.fname));
+                html.extend_from_slice(
+                    format!(
+                        "<p height=\"0pt\" width=\"0pt\" align=\"center\"><img recindex=\"{:05}\" align=\"baseline\" width=\"100%\"/></p><mbp:pagebreak/>",
+                        images.len()
+                    )
+                    .as_bytes(),
+                );
+            }
+        }
+        html.extend_from_slice(b"</body></html>");
+        // 没有目录时 guide 指向正文开头
+        if !merged {
+            targets.truncate(1);
+        }
+        for (pos, target) in placeholders.iter().zip(targets) {
+            let filepos = format!("{:010}", target);
+            html[*pos..*pos + 10].copy_from_slice(filepos.as_bytes());
+        }
+        (html, images)
+    }
+
+    fn render_exth(&self, first_image: &[u8]) -> Vec<u8> {
+        let mut records: Vec<(u32, Vec<u8>)> = vec![
+            (100, b"mikack-cli".to_vec()),
+            (101, b"mikack".to_vec()),
+            (106, Utc::now().to_rfc3339().into_bytes()),
+            (112, self.volume.chapters[0].url.clone().into_bytes()),
+            (201, 0u32.to_be_bytes().to_vec()),
+            (122, b"true".to_vec()),
+            (123, b"comic".to_vec()),
+            (124, b"none".to_vec()),
+            (501, b"EBOK".to_vec()),
+            (503, self.volume.title.clone().into_bytes()),
+        ];
+        if let Some((width, height)) = image_dimensions(first_image) {
+            records.push((126, format!("{}x{}", width, height).into_bytes()));
+        }
+        if self.rtl {
+            records.push((525, b"horizontal-rl".to_vec()));
+            records.push((527, b"rtl".to_vec()));
+        } else {
+            records.push((525, b"horizontal-lr".to_vec()));
+            records.push((527, b"ltr".to_vec()));
+        }
+        let mut body = vec![];
+        for (kind, data) in &records {
+            body.extend_from_slice(&kind.to_be_bytes());
+            body.extend_from_slice(&(data.len() as u32 + 8).to_be_bytes());
+            body.extend_from_slice(data);
+        }
+        let mut exth = b"EXTH".to_vec();
+        exth.extend_from_slice(&(body.len() as u32 + 12).to_be_bytes());
+        exth.extend_from_slice(&(records.len() as u32).to_be_bytes());
+        exth.extend(body);
+        pad_to_four(&mut exth);
+        exth
+    }
+
+    /// 由正文 HTML 及图片生成完整的 MOBI 文件
+    fn build(&self, html: &[u8], images: &[Vec<u8>]) -> Vec<u8> {
+        let text_records: Vec<&[u8]> = html.chunks(TEXT_RECORD_SIZE).collect();
+        let first_image_index = text_records.len() as u32 + 1;
+        let last_content_index = first_image_index + images.len() as u32 - 1;
+        let flis_index = last_content_index + 1;
+        let fcis_index = flis_index + 1;
+
+        // 记录 0：PalmDOC 头 + MOBI 头 + EXTH + 书名
+        let mut record0 = vec![];
+        record0.extend_from_slice(&1u16.to_be_bytes()); // 不压缩
+        record0.extend_from_slice(&0u16.to_be_bytes());
+        record0.extend_from_slice(&(html.len() as u32).to_be_bytes());
+        record0.extend_from_slice(&(text_records.len() as u16).to_be_bytes());
+        record0.extend_from_slice(&(TEXT_RECORD_SIZE as u16).to_be_bytes());
+        record0.extend_from_slice(&0u32.to_be_bytes()); // 无加密
+        let exth = self.render_exth(&images[0]);
+        let full_name = self.volume.title.as_bytes();
+        let full_name_offset = 16 + MOBI_HEADER_LEN + exth.len() as u32;
+        let unique_id = Uuid::new_v4().as_bytes()[0..4]
+            .iter()
+            .fold(0u32, |id, b| (id << 8) | *b as u32);
+        let mut header = vec![];
+        header.extend_from_slice(b"MOBI");
+        for n in &[MOBI_HEADER_LEN, 2, 65001, unique_id, 6] {
+            header.extend_from_slice(&n.to_be_bytes());
+        }
+        // 各类索引（orthographic/inflection/names/keys/extra 0-5）
+        for _ in 0..10 {
+            header.extend_from_slice(&NULL_INDEX.to_be_bytes());
+        }
+        for n in &[
+            first_image_index,
+            full_name_offset,
+            full_name.len() as u32,
+            9, // locale: en
+            0,
+            0,
+            6,
+            first_image_index,
+            0,
+            0,
+            0,
+            0,
+            0x50, // 包含 EXTH
+        ] {
+            header.extend_from_slice(&n.to_be_bytes());
+        }
+        header.extend_from_slice(&[0u8; 32]);
+        for n in &[NULL_INDEX, NULL_INDEX, 0, 0, 0, 0, 0] {
+            header.extend_from_slice(&n.to_be_bytes());
+        }
+        header.extend_from_slice(&1u16.to_be_bytes());
+        header.extend_from_slice(&(last_content_index as u16).to_be_bytes());
+        for n in &[
+            1, fcis_index, 1, flis_index, 1, 0, 0, NULL_INDEX, 0, NULL_INDEX, NULL_INDEX,
+            0, // 文本记录无尾部数据
+            NULL_INDEX,
+        ] {
+            header.extend_from_slice(&n.to_be_bytes());
+        }
+        record0.extend(header);
+        record0.extend(exth);
+        record0.extend_from_slice(full_name);
+        record0.extend_from_slice(&[0, 0]);
+        pad_to_four(&mut record0);
+
+        let mut fcis = b"FCIS\0\0\0\x14\0\0\0\x10\0\0\0\x01\0\0\0\0".to_vec();
+        fcis.extend_from_slice(&(html.len() as u32).to_be_bytes());
+        fcis.extend_from_slice(b"\0\0\0\0\0\0\0\x20\0\0\0\x08\0\x01\0\x01\0\0\0\0");
+
+        let mut records: Vec<&[u8]> = vec![record0.as_slice()];
+        records.extend(text_records);
+        records.extend(images.iter().map(|img| img.as_slice()));
+        records.push(FLIS_RECORD);
+        records.push(&fcis);
+        records.push(EOF_RECORD);
+
+        let mut mobi = palm_db_header(&self.volume.title, &records);
+        for record in records {
+            mobi.extend_from_slice(record);
+        }
+        mobi
+    }
+}
+
+impl Exporter for Mobi {
+    fn from_cache(base_dirs: &[&str], options: &Options) -> Result<Self> {
+        let volume = Volume::from_cache(base_dirs, options)?;
+        Ok(Self {
+            volume,
+            rtl: options.rtl,
+        })
+    }
+
+    fn expo(&self) -> Result<PathBuf> {
+        let (html, image_paths) = self.render_html();
+        if image_paths.is_empty() {
+            return Err(err_msg("No pages to export"));
+        }
+        let mut images = vec![];
+        for path in &image_paths {
+            let bytes = read(path)?;
+            if !(bytes.starts_with(b"\xff\xd8")
+                || bytes.starts_with(b"\x89PNG")
+                || bytes.starts_with(b"GIF8"))
+            {
+                return Err(err_msg(format!(
+                    "Unsupported image format for mobi: {}",
+                    path.display()
+                )));
+            }
+            images.push(bytes);
+        }
+
+        let mobi_file = self.volume.output_path("mobi")?;
+        let mut file = File::create(&mobi_file)?;
+        file.write_all(&self.build(&html, &images))?;
+        Ok(mobi_file)
+    }
+}
+
+/// PalmDB 文件头及记录列表
+fn palm_db_header(title: &str, records: &[&[u8]]) -> Vec<u8> {
+    let mut name: Vec<u8> = title
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c as u8
+            } else {
+                b'_'
+            }
+        })
+        .take(31)
+        .collect();
+    if name.iter().all(|c| *c == b'_') {
+        name = b"mikack".to_vec();
+    }
+    name.resize(32, 0);
+    let now = Utc::now().timestamp() as u32;
+    let mut header = name;
+    header.extend_from_slice(&0u16.to_be_bytes()); // attributes
+    header.extend_from_slice(&0u16.to_be_bytes()); // version
+    header.extend_from_slice(&now.to_be_bytes());
+    header.extend_from_slice(&now.to_be_bytes());
+    header.extend_from_slice(&[0u8; 12]);
+    header.extend_from_slice(&0u32.to_be_bytes()); // sort info
+    header.extend_from_slice(b"BOOKMOBI");
+    header.extend_from_slice(&(2 * records.len() as u32 - 1).to_be_bytes());
+    header.extend_from_slice(&0u32.to_be_bytes());
+    header.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    let mut offset = 78 + 8 * records.len() as u32 + 2;
+    for (i, record) in records.iter().enumerate() {
+        header.extend_from_slice(&offset.to_be_bytes());
+        header.extend_from_slice(&(2 * i as u32).to_be_bytes());
+        offset += record.len() as u32;
+    }
+    header.extend_from_slice(&[0, 0]);
+    header
+}
+
+/// 转义并将非 ASCII 字符替换为字符引用，避免在文本记录边界截断多字节字符
+fn ascii_html(text: &str) -> Vec<u8> {
+    xml_syntax_escaped(text)
+        .chars()
+        .map(|c| {
+            if c.is_ascii() {
+                c.to_string()
+            } else {
+                format!("&#{};", c as u32)
+            }
+        })
+        .collect::<String>()
+        .into_bytes()
+}
+
+fn pad_to_four(bytes: &mut Vec<u8>) {
+    while bytes.len() % 4 != 0 {
+        bytes.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1x1 的 PNG 及 GIF 头部，足以识别格式和尺寸
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\x03\x20\0\0\x04\xb0\x08\x02\0\0\0";
+    const GIF: &[u8] = b"GIF89a\x01\0\x01\0\x80\0\0";
+
+    fn u16_at(bytes: &[u8], i: usize) -> usize {
+        u16::from_be_bytes([bytes[i], bytes[i + 1]]) as usize
+    }
+
+    fn u32_at(bytes: &[u8], i: usize) -> usize {
+        u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]) as usize
+    }
+
+    /// 按 PalmDB 记录列表拆分文件
+    fn records(mobi: &[u8]) -> Vec<&[u8]> {
+        assert_eq!(&mobi[60..68], b"BOOKMOBI");
+        let count = u16_at(mobi, 76);
+        assert_eq!(u32_at(mobi, 68), 2 * count - 1);
+        let offsets = (0..count)
+            .map(|i| u32_at(mobi, 78 + 8 * i))
+            .chain(std::iter::once(mobi.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(offsets[0], 78 + 8 * count + 2);
+        offsets
+            .windows(2)
+            .map(|w| {
+                assert!(w[0] <= w[1]);
+                &mobi[w[0]..w[1]]
+            })
+            .collect()
+    }
+
+    fn mobi(html_len: usize, images: &[Vec<u8>], rtl: bool) -> Vec<u8> {
+        let chapter = Chapter::new("第1话", "https://www.dm5.com/m1029843/", 0);
+        let mobi = Mobi {
+            volume: Volume::for_test("海贼王 <1>", vec![chapter]),
+            rtl,
+        };
+        mobi.build(&vec![b'a'; html_len], images)
+    }
+
+    #[test]
+    fn test_record_layout() {
+        let images = vec![PNG.to_vec(), GIF.to_vec()];
+        let file = mobi(TEXT_RECORD_SIZE * 2 + 1, &images, false);
+        let records = records(&file);
+        // 记录 0、3 条文本记录、2 张图片、FLIS、FCIS、EOF
+        assert_eq!(records.len(), 1 + 3 + 2 + 3);
+        let record0 = records[0];
+        assert_eq!(u32_at(record0, 4), TEXT_RECORD_SIZE * 2 + 1);
+        assert_eq!(u16_at(record0, 8), 3);
+        assert_eq!(u16_at(record0, 10), TEXT_RECORD_SIZE);
+        assert_eq!(records[1].len(), TEXT_RECORD_SIZE);
+        assert_eq!(records[3].len(), 1);
+
+        assert_eq!(&record0[16..20], b"MOBI");
+        assert_eq!(u32_at(record0, 20), MOBI_HEADER_LEN as usize);
+        let first_image = u32_at(record0, 16 + 0x5c);
+        assert_eq!(first_image, 4);
+        assert_eq!(u32_at(record0, 80), first_image);
+        assert_eq!(records[first_image], PNG);
+        assert_eq!(records[first_image + 1], GIF);
+        assert_eq!(u16_at(record0, 192), 1);
+        assert_eq!(u16_at(record0, 194), first_image + 1);
+        assert_eq!(&records[u32_at(record0, 0xd0)][..4], b"FLIS");
+        assert_eq!(&records[u32_at(record0, 0xc8)][..4], b"FCIS");
+        assert_eq!(*records.last().unwrap(), EOF_RECORD);
+
+        let name_offset = u32_at(record0, 84);
+        let name_len = u32_at(record0, 88);
+        assert_eq!(
+            &record0[name_offset..name_offset + name_len],
+            "海贼王 <1>".as_bytes()
+        );
+        assert_eq!(record0.len() % 4, 0);
+    }
+
+    #[test]
+    fn test_exth() {
+        let file = mobi(10, &[PNG.to_vec()], true);
+        let record0 = records(&file)[0];
+        assert_eq!(u32_at(record0, 0x80) & 0x40, 0x40);
+        let exth = &record0[16 + MOBI_HEADER_LEN as usize..];
+        assert_eq!(&exth[..4], b"EXTH");
+        let len = u32_at(exth, 4);
+        let count = u32_at(exth, 8);
+        let mut entries = HashMap::new();
+        let mut i = 12;
+        for _ in 0..count {
+            let (kind, size) = (u32_at(exth, i), u32_at(exth, i + 4));
+            assert!(size >= 8);
+            entries.insert(kind, &exth[i + 8..i + size]);
+            i += size;
+        }
+        assert_eq!(i, len);
+        // EXTH 补齐到 4 字节后紧接书名
+        assert_eq!(
+            u32_at(record0, 84),
+            16 + MOBI_HEADER_LEN as usize + (len + 3) / 4 * 4
+        );
+        assert_eq!(entries[&122], b"true");
+        assert_eq!(entries[&126], b"800x1200");
+        assert_eq!(entries[&527], b"rtl");
+        assert_eq!(entries[&503], "海贼王 <1>".as_bytes());
+        assert_eq!(entries[&112], b"https://www.dm5.com/m1029843/");
+    }
+
+    #[test]
+    fn test_palm_db_name() {
+        let header = palm_db_header("海贼王 One", &[&b"ab"[..]]);
+        assert_eq!(&header[..8], b"____One\0");
+        // 没有可用字符时使用默认名称
+        let header = palm_db_header("海贼王", &[&b"ab"[..]]);
+        assert_eq!(&header[..7], b"mikack\0");
+        let header = palm_db_header(&"a".repeat(40), &[&b"ab"[..]]);
+        assert_eq!(&header[..32], [&[b'a'; 31][..], &[0]].concat().as_slice());
+    }
+}
@@ -0,0 +1,341 @@
+use super::*;
+use crate::{jpeg_frame, VERSION};
+use flate2::{write::ZlibEncoder, Compression};
+use std::fs::read;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+/// 每页一张图片的 PDF，页面尺寸与图片一致
+pub struct Pdf {
+    volume: Volume,
+    rtl: bool,
+}
+
+// 固定的对象编号，之后依次是大纲条目和页面
+const CATALOG_ID: usize = 1;
+const PAGES_ID: usize = 2;
+const OUTLINES_ID: usize = 3;
+const INFO_ID: usize = 4;
+
+/// 嵌入 PDF 的图片数据
+struct PdfImage {
+    width: u32,
+    height: u32,
+    color_space: &'static str,
+    filter: &'static str,
+    decode: &'static str,
+    data: Vec<u8>,
+}
+
+impl PdfImage {
+    /// JPEG 直接嵌入，其它格式解码后以 Flate 压缩
+    fn load(bytes: Vec<u8>) -> Result<Self> {
+        if let Some((width, height, components)) = jpeg_frame(&bytes) {
+            let (color_space, decode) = match components {
+                1 => ("/DeviceGray", ""),
+                // Adobe 的 CMYK JPEG 是反相存储的
+                4 => ("/DeviceCMYK", " /Decode [1 0 1 0 1 0 1 0]"),
+                _ => ("/DeviceRGB", ""),
+            };
+            return Ok(Self {
+                width,
+                height,
+                color_space,
+                filter: "/DCTDecode",
+                decode,
+                data: bytes,
+            });
+        }
+        let rgb = image::load_from_memory(&bytes)?.to_rgb();
+        let (width, height) = rgb.dimensions();
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&rgb.into_raw())?;
+        Ok(Self {
+            width,
+            height,
+            color_space: "/DeviceRGB",
+            filter: "/FlateDecode",
+            decode: "",
+            data: encoder.finish()?,
+        })
+    }
+}
+
+/// 记录对象偏移的 PDF 写入器
+struct PdfWriter<W: Write> {
+    out: W,
+    pos: usize,
+    offsets: Vec<usize>,
+}
+
+impl<W: Write> PdfWriter<W> {
+    fn new(out: W, objects: usize) -> Self {
+        Self {
+            out,
+            pos: 0,
+            offsets: vec![0; objects + 1],
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.out.write_all(bytes)?;
+        self.pos += bytes.len();
+        Ok(())
+    }
+
+    fn object(&mut self, id: usize, dict: &str) -> Result<()> {
+        self.offsets[id] = self.pos;
+        self.write(format!("{} 0 obj\n{}\nendobj\n", id, dict).as_bytes())
+    }
+
+    fn stream(&mut self, id: usize, dict: &str, data: &[u8]) -> Result<()> {
+        self.offsets[id] = self.pos;
+        self.write(
+            format!(
+                "{} 0 obj\n<< {} /Length {} >>\nstream\n",
+                id,
+                dict,
+                data.len()
+            )
+            .as_bytes(),
+        )?;
+        self.write(data)?;
+        self.write(b"\nendstream\nendobj\n")
+    }
+
+    fn finish(mut self) -> Result<()> {
+        let xref_pos = self.pos;
+        let mut xref = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len());
+        for offset in &self.offsets[1..] {
+            xref.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        xref.push_str(&format!(
+            "trailer\n<< /Size {} /Root {} 0 R /Info {} 0 R >>\nstartxref\n{}\n%%EOF\n",
+            self.offsets.len(),
+            CATALOG_ID,
+            INFO_ID,
+            xref_pos
+        ));
+        self.write(xref.as_bytes())?;
+        Ok(self.out.flush()?)
+    }
+}
+
+impl Exporter for Pdf {
+    fn from_cache(base_dirs: &[&str], options: &Options) -> Result<Self> {
+        let volume = Volume::from_cache(base_dirs, options)?;
+        Ok(Self {
+            volume,
+            rtl: options.rtl,
+        })
+    }
+
+    fn expo(&self) -> Result<PathBuf> {
+        // 每个章节一个大纲条目，指向章节的首页
+        let chapters: Vec<usize> = (0..self.volume.chapters.len())
+            .filter(|i| !self.volume.chapters[*i].pages.is_empty())
+            .collect();
+        let total_pages: usize = chapters
+            .iter()
+            .map(|i| self.volume.chapters[*i].pages.len())
+            .sum();
+        if total_pages == 0 {
+            return Err(err_msg("No pages to export"));
+        }
+        let first_outline_id = INFO_ID + 1;
+        let first_page_id = first_outline_id + chapters.len();
+        // 每页占用三个对象：页面、内容流、图片
+        let page_id = |n: usize| first_page_id + n * 3;
+
+        let pdf_file = self.volume.output_path("pdf")?;
+        let file = BufWriter::new(File::create(&pdf_file)?);
+        let mut writer = PdfWriter::new(file, page_id(total_pages) - 1);
+        writer.write(b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n")?;
+
+        let preferences = if self.rtl {
+            " /ViewerPreferences << /Direction /R2L >>"
+        } else {
+            ""
+        };
+        writer.object(
+            CATALOG_ID,
+            &format!(
+                "<< /Type /Catalog /Pages {} 0 R /Outlines {} 0 R /PageMode /UseOutlines{} >>",
+                PAGES_ID, OUTLINES_ID, preferences
+            ),
+        )?;
+        let kids = (0..total_pages)
+            .map(|n| format!("{} 0 R", page_id(n)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writer.object(
+            PAGES_ID,
+            &format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids, total_pages),
+        )?;
+        writer.object(
+            OUTLINES_ID,
+            &format!(
+                "<< /Type /Outlines /First {} 0 R /Last {} 0 R /Count {} >>",
+                first_outline_id,
+                first_outline_id + chapters.len() - 1,
+                chapters.len()
+            ),
+        )?;
+        writer.object(
+            INFO_ID,
+            &format!(
+                "<< /Title {} /Producer {} >>",
+                pdf_text(&self.volume.title),
+                pdf_text(&format!("mikack-cli ({})", VERSION))
+            ),
+        )?;
+
+        let mut n = 0;
+        for (oi, i) in chapters.iter().enumerate() {
+            let chapter = &self.volume.chapters[*i];
+            let outline_id = first_outline_id + oi;
+            let mut outline = format!(
+                "<< /Title {} /Parent {} 0 R /Dest [{} 0 R /Fit]",
+                pdf_text(&chapter.title),
+                OUTLINES_ID,
+                page_id(n)
+            );
+            if oi > 0 {
+                outline.push_str(&format!(" /Prev {} 0 R", outline_id - 1));
+            }
+            if oi + 1 < chapters.len() {
+                outline.push_str(&format!(" /Next {} 0 R", outline_id + 1));
+            }
+            outline.push_str(" >>");
+            writer.object(outline_id, &outline)?;
+
+            for page in &chapter.pages {
+                let img = PdfImage::load(read(self.volume.cache_path(*i, &page.fname))?)?;
+                let id = page_id(n);
+                writer.object(
+                    id,
+                    &format!(
+                        "<< /Type /Page /Parent {} 0 R /MediaBox [0 0 {} {}] /Contents {} 0 R /Resources << /XObject << /Im0 {} 0 R >> >> >>",
+                        PAGES_ID,
+                        img.width,
+                        img.height,
+                        id + 1,
+                        id + 2
+                    ),
+                )?;
+                let content = format!("q {} 0 0 {} 0 0 cm /Im0 Do Q", img.width, img.height);
+                writer.stream(id + 1, "", content.as_bytes())?;
+                writer.stream(
+                    id + 2,
+                    &format!(
+                        "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace {} /BitsPerComponent 8 /Filter {}{}",
+                        img.width, img.height, img.color_space, img.filter, img.decode
+                    ),
+                    &img.data,
+                )?;
+                n += 1;
+            }
+        }
+        writer.finish()?;
+        Ok(pdf_file)
+    }
+}
+
+/// 以 UTF-16BE 十六进制字符串表示的文本
+fn pdf_text(text: &str) -> String {
+    let mut hex = String::from("<FEFF");
+    for unit in text.encode_utf16() {
+        hex.push_str(&format!("{:04X}", unit));
+    }
+    hex.push('>');
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use image::{DynamicImage, ImageBuffer, ImageOutputFormat, Rgb};
+    use std::io::Read;
+
+    // 只有 SOF0 帧头的 JPEG：4x3，单通道
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe0\0\x10JFIF\0\x01\x01\0\0\x01\0\x01\0\0\xff\xc0\0\x0b\x08\0\x03\0\x04\x01\x01\x11\0\xff\xd9";
+
+    #[test]
+    fn test_xref_offsets() {
+        let mut out = vec![];
+        let mut writer = PdfWriter::new(&mut out, 4);
+        writer.write(b"%PDF-1.4\n").unwrap();
+        writer.object(CATALOG_ID, "<< /Type /Catalog >>").unwrap();
+        writer.stream(3, "", b"q Q").unwrap();
+        writer.object(PAGES_ID, "<< /Type /Pages >>").unwrap();
+        writer
+            .stream(INFO_ID, "/Subtype /Image", &[0, 1, 2])
+            .unwrap();
+        writer.finish().unwrap();
+
+        let text = String::from_utf8_lossy(&out).to_string();
+        let startxref = text.rfind("startxref\n").unwrap();
+        let xref_pos: usize = text[startxref + 10..]
+            .lines()
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let xref = &text[xref_pos..];
+        assert!(xref.starts_with("xref\n0 5\n0000000000 65535 f \n"));
+        let entries = &xref["xref\n0 5\n".len()..];
+        for id in 1..=4 {
+            // 每条记录固定为 20 字节
+            let entry = &entries[id * 20..(id + 1) * 20];
+            assert!(entry.ends_with(" 00000 n \n"));
+            let offset: usize = entry[..10].parse().unwrap();
+            assert!(text[offset..].starts_with(&format!("{} 0 obj\n", id)));
+        }
+        assert!(text.contains("trailer\n<< /Size 5 /Root 1 0 R /Info 4 0 R >>"));
+        assert!(text.ends_with("%%EOF\n"));
+    }
+
+    #[test]
+    fn test_stream_length() {
+        let mut out = vec![];
+        let mut writer = PdfWriter::new(&mut out, 1);
+        writer.stream(1, "", b"q Q").unwrap();
+        assert_eq!(
+            out,
+            b"1 0 obj\n<<  /Length 3 >>\nstream\nq Q\nendstream\nendobj\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_load_jpeg() {
+        let img = PdfImage::load(JPEG.to_vec()).unwrap();
+        assert_eq!((img.width, img.height), (4, 3));
+        assert_eq!(img.color_space, "/DeviceGray");
+        assert_eq!(img.filter, "/DCTDecode");
+        assert_eq!(img.data, JPEG);
+    }
+
+    #[test]
+    fn test_load_png() {
+        let buf = ImageBuffer::from_pixel(2, 3, Rgb([255u8, 0, 0]));
+        let mut png = vec![];
+        DynamicImage::ImageRgb8(buf)
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        let img = PdfImage::load(png).unwrap();
+        assert_eq!((img.width, img.height), (2, 3));
+        assert_eq!(img.filter, "/FlateDecode");
+        let mut raw = vec![];
+        ZlibDecoder::new(img.data.as_slice())
+            .read_to_end(&mut raw)
+            .unwrap();
+        assert_eq!(raw, [255, 0, 0].repeat(6));
+    }
+
+    #[test]
+    fn test_pdf_text() {
+        assert_eq!(pdf_text("第1话"), "<FEFF7B2C00318BDD>");
+        assert_eq!(pdf_text(""), "<FEFF>");
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashSet;
+use unicode_normalization::UnicodeNormalization;
+
+/// 名称的最大字节数，为 `.part` 及序号后缀预留空间（常见文件系统上限为 255）
+pub const MAX_NAME_BYTES: usize = 200;
+
+// Windows 的保留设备名，带扩展名同样不可用
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// 将远程数据（章节标题、页面文件名等）转换为可以安全用作单个路径组件的名称。
+/// 统一为 NFC 形式，替换路径分隔符、非法字符及控制字符，去除首尾的空白和点，
+/// 避开保留名称并限制长度
+pub fn sanitize(name: &str) -> String {
+    let replaced: String = name
+        .nfc()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // 开头的点会产生隐藏文件或 `..`，Windows 不允许以点或空格结尾
+    let mut safe = replaced
+        .trim_matches(|c: char| c.is_whitespace() || c == '.')
+        .to_string();
+    if safe.is_empty() {
+        safe.push('_');
+    }
+    let stem = safe.split('.').next().unwrap_or("").trim_end();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        safe.insert(0, '_');
+    }
+    truncate(&safe, MAX_NAME_BYTES)
+}
+
+/// 在 taken 中不重复的目录名，重复时追加 ` (2)`、` (3)` 等序号
+pub fn unique_name(name: &str, taken: &mut HashSet<String>) -> String {
+    unique(name, "", taken)
+}
+
+/// 在 taken 中不重复的文件名，序号追加在扩展名之前
+pub fn unique_file_name(name: &str, taken: &mut HashSet<String>) -> String {
+    let (stem, ext) = split_ext(name);
+    unique(stem, ext, taken)
+}
+
+// 比较时不区分大小写，以兼容大小写不敏感的文件系统
+fn unique(stem: &str, ext: &str, taken: &mut HashSet<String>) -> String {
+    let mut candidate = format!("{}{}", stem, ext);
+    let mut n = 1;
+    while !taken.insert(candidate.to_lowercase()) {
+        n += 1;
+        candidate = format!("{} ({}){}", stem, n, ext);
+    }
+    candidate
+}
+
+/// 截断到 max 字节以内，保留扩展名且不截断多字节字符
+fn truncate(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+    let (stem, ext) = split_ext(name);
+    let mut end = max - ext.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    let stem = stem[..end].trim_end_matches(|c: char| c.is_whitespace() || c == '.');
+    format!("{}{}", stem, ext)
+}
+
+/// 拆分出较短的 ASCII 扩展名（eg: `.jpg`），章节标题中的 `第1.5话` 不视为扩展名
+fn split_ext(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i)
+            if i > 0
+                && name.len() - i <= 8
+                && name[i + 1..].chars().all(|c| c.is_ascii_alphanumeric())
+                && name[i + 1..].chars().any(|c| c.is_ascii_alphabetic()) =>
+        {
+            name.split_at(i)
+        }
+        _ => (name, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("第1话: 开始?"), "第1话_ 开始_");
+        assert_eq!(sanitize("../etc/passwd"), "_etc_passwd");
+        assert_eq!(sanitize(" .hidden. "), "hidden");
+        assert_eq!(sanitize("..."), "_");
+        assert_eq!(sanitize("a\u{0}b"), "a_b");
+        // NFD 的 `é` 统一为 NFC
+        assert_eq!(sanitize("e\u{301}"), "\u{e9}");
+    }
+
+    #[test]
+    fn test_sanitize_reserved_names() {
+        assert_eq!(sanitize("CON"), "_CON");
+        assert_eq!(sanitize("con.txt"), "_con.txt");
+        assert_eq!(sanitize("lpt1 .jpg"), "_lpt1 .jpg");
+        assert_eq!(sanitize("CONSOLE"), "CONSOLE");
+    }
+
+    #[test]
+    fn test_truncate_multibyte() {
+        let name = "第".repeat(100);
+        let truncated = sanitize(&name);
+        assert!(truncated.len() <= MAX_NAME_BYTES);
+        assert_eq!(truncated, "第".repeat(66));
+
+        let truncated = sanitize(&format!("{}.jpg", name));
+        assert!(truncated.len() <= MAX_NAME_BYTES);
+        assert_eq!(truncated, format!("{}.jpg", "第".repeat(65)));
+    }
+
+    #[test]
+    fn test_split_ext() {
+        assert_eq!(split_ext("001.jpg"), ("001", ".jpg"));
+        assert_eq!(split_ext("第1.5话"), ("第1.5话", ""));
+        assert_eq!(split_ext("1.5"), ("1.5", ""));
+        assert_eq!(split_ext(".jpg"), (".jpg", ""));
+        assert_eq!(split_ext("a.verylongext"), ("a.verylongext", ""));
+    }
+
+    #[test]
+    fn test_unique_names() {
+        let mut taken = HashSet::new();
+        assert_eq!(unique_file_name("a.jpg", &mut taken), "a.jpg");
+        assert_eq!(unique_file_name("A.JPG", &mut taken), "A (2).JPG");
+        assert_eq!(unique_file_name("a.jpg", &mut taken), "a (3).jpg");
+        assert_eq!(unique_name("第1.5话", &mut taken), "第1.5话");
+        assert_eq!(unique_name("第1.5话", &mut taken), "第1.5话 (2)");
+    }
+}
@@ -0,0 +1,282 @@
+use crate::{platform, save_to, Origin};
+use chrono::{offset::Utc, DateTime};
+use mikack::error::*;
+use mikack::models::Chapter;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const HISTORY_FILE: &'static str = "history.jsonl";
+
+/// 一条下载记录。仅下载到缓存时 format 和 output 为空，
+/// 每次导出另有一条带格式和输出路径的记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// RFC 3339 格式的时间
+    pub timestamp: String,
+    pub platform: String,
+    pub comic_url: Option<String>,
+    pub comic_title: Option<String>,
+    pub chapter_url: String,
+    pub title: String,
+    pub pages: usize,
+    pub format: Option<String>,
+    pub output: Option<String>,
+    /// 缓存或输出文件的字节数
+    pub size: u64,
+}
+
+impl Entry {
+    pub fn new(chapter: &Chapter, origin: &Origin) -> Self {
+        Self {
+            timestamp: Utc::now().to_rfc3339(),
+            platform: platform(&chapter.url),
+            comic_url: origin.comic_url.clone(),
+            comic_title: origin.comic_title.clone(),
+            chapter_url: chapter.url.clone(),
+            title: chapter.title.clone(),
+            pages: chapter.pages.len(),
+            format: None,
+            output: None,
+            size: 0,
+        }
+    }
+
+    /// 标题、漫画标题或链接中包含关键字（不区分大小写）
+    pub fn matches(&self, keywords: &str) -> bool {
+        let keywords = keywords.to_lowercase();
+        [
+            Some(&self.title),
+            self.comic_title.as_ref(),
+            Some(&self.chapter_url),
+            self.comic_url.as_ref(),
+        ]
+        .iter()
+        .filter_map(|field| *field)
+        .any(|field| field.to_lowercase().contains(&keywords))
+    }
+
+    pub fn time(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.timestamp)
+            .ok()
+            .map(|time| time.with_timezone(&Utc))
+    }
+}
+
+/// 追加写入的下载历史，保存在数据目录的 history.jsonl 中（每行一条 JSON 记录）
+pub struct History {
+    data_dir: PathBuf,
+}
+
+impl History {
+    pub fn open(data_dir: &Path) -> Self {
+        Self {
+            data_dir: data_dir.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.data_dir.join(HISTORY_FILE)
+    }
+
+    pub fn append(&self, entry: &Entry) -> Result<()> {
+        fs::create_dir_all(&self.data_dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path())?;
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// 全部记录（按时间先后），无法解析的行会被忽略
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let path = self.path();
+        if !path.is_file() {
+            return Ok(vec![]);
+        }
+        Ok(fs::read_to_string(path)?
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    /// 章节最近的一条记录
+    pub fn find(&self, chapter_url: &str) -> Result<Option<Entry>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .rev()
+            .find(|entry| entry.chapter_url == chapter_url))
+    }
+
+    pub fn search(&self, keywords: &str) -> Result<Vec<Entry>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|entry| entry.matches(keywords))
+            .collect())
+    }
+
+    /// 删除不满足 retain 的记录，返回删除的数量
+    pub fn prune<F>(&self, retain: F) -> Result<usize>
+    where
+        F: Fn(usize, &Entry) -> bool,
+    {
+        let entries = self.entries()?;
+        let total = entries.len();
+        let mut kept = String::new();
+        for (i, entry) in entries.iter().enumerate() {
+            if retain(i, entry) {
+                kept.push_str(&serde_json::to_string(entry)?);
+                kept.push('\n');
+            }
+        }
+        let pruned = total - kept.lines().count();
+        if pruned > 0 {
+            save_to(
+                self.data_dir.clone(),
+                HISTORY_FILE,
+                &kept.as_bytes().to_vec(),
+            )?;
+        }
+        Ok(pruned)
+    }
+
+    /// 只保留最近的 keep 条记录，同时删除早于 before 的记录，返回删除的数量
+    pub fn prune_older(&self, keep: Option<usize>, before: Option<DateTime<Utc>>) -> Result<usize> {
+        let total = self.entries()?.len();
+        let keep = keep.unwrap_or(total);
+        self.prune(|i, entry| {
+            // 无法解析时间的记录不按时间删除
+            let recent = match (before, entry.time()) {
+                (Some(before), Some(time)) => time >= before,
+                _ => true,
+            };
+            i + keep >= total && recent
+        })
+    }
+}
+
+/// 文件或目录（递归）的字节数
+pub fn path_size(path: &Path) -> u64 {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| path_size(&entry.path()))
+                    .sum()
+            })
+            .unwrap_or(0),
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn entry(title: &str, timestamp: &str) -> Entry {
+        Entry {
+            timestamp: timestamp.to_string(),
+            platform: "example.com".to_string(),
+            comic_url: Some("https://example.com/comic/1".to_string()),
+            comic_title: Some("One Piece".to_string()),
+            chapter_url: format!("https://example.com/chapter/{}", title),
+            title: title.to_string(),
+            pages: 10,
+            format: None,
+            output: None,
+            size: 0,
+        }
+    }
+
+    /// 写入记录的临时历史文件
+    fn history(name: &str, entries: &[Entry]) -> History {
+        let data_dir = std::env::temp_dir().join(format!(
+            "mikack-cli-history-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&data_dir);
+        let history = History::open(&data_dir);
+        for entry in entries {
+            history.append(entry).unwrap();
+        }
+        history
+    }
+
+    fn titles(history: &History) -> Vec<String> {
+        history
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.title)
+            .collect()
+    }
+
+    #[test]
+    fn test_matches() {
+        let entry = entry("第1话", "2020-01-01T00:00:00+00:00");
+        assert!(entry.matches("第1"));
+        assert!(entry.matches("one piece"));
+        assert!(entry.matches("EXAMPLE.COM/CHAPTER"));
+        assert!(!entry.matches("naruto"));
+    }
+
+    #[test]
+    fn test_search() {
+        let mut other = entry("Chapter 2", "2020-01-02T00:00:00+00:00");
+        other.comic_title = Some("Naruto".to_string());
+        let history = history(
+            "search",
+            &[entry("Chapter 1", "2020-01-01T00:00:00+00:00"), other],
+        );
+        let found = history.search("naruto").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].title, "Chapter 2");
+        assert_eq!(history.search("chapter").unwrap().len(), 2);
+        assert!(history.search("bleach").unwrap().is_empty());
+        fs::remove_dir_all(&history.data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_keep() {
+        let entries = (1..=4)
+            .map(|i| entry(&i.to_string(), &Utc::now().to_rfc3339()))
+            .collect::<Vec<_>>();
+        let history = history("keep", &entries);
+        assert_eq!(history.prune_older(Some(2), None).unwrap(), 2);
+        assert_eq!(titles(&history), vec!["3", "4"]);
+        assert_eq!(history.prune_older(Some(5), None).unwrap(), 0);
+        assert_eq!(history.prune_older(Some(0), None).unwrap(), 2);
+        assert!(titles(&history).is_empty());
+        fs::remove_dir_all(&history.data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_older_than() {
+        let now = Utc::now();
+        let history = history(
+            "older-than",
+            &[
+                entry("old", &(now - Duration::days(30)).to_rfc3339()),
+                entry("invalid", "yesterday"),
+                entry("new", &now.to_rfc3339()),
+            ],
+        );
+        let before = Some(now - Duration::days(7));
+        assert_eq!(history.prune_older(None, before).unwrap(), 1);
+        assert_eq!(titles(&history), vec!["invalid", "new"]);
+        // 两个条件同时生效
+        assert_eq!(history.prune_older(Some(1), before).unwrap(), 1);
+        assert_eq!(titles(&history), vec!["new"]);
+        fs::remove_dir_all(&history.data_dir).unwrap();
+    }
+}
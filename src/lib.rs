@@ -1,25 +1,35 @@
 use indicatif::{ProgressBar, ProgressStyle};
 use lazy_static::lazy_static;
 pub use mikack::error::*;
+use mikack::models::Chapter;
 use regex::Regex;
-use reqwest::{
-    blocking::{Client, Response},
-    header::{HeaderMap, HeaderName, HeaderValue},
-};
-use std::collections::HashMap;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::fs::File;
 use std::io::{stdin, stdout, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub mod cli;
+pub mod downloader;
 pub mod exporters;
+pub mod fsname;
+pub mod history;
+pub mod library;
+pub mod opds;
+pub mod output;
+pub mod queue;
+pub mod ratelimit;
+pub mod server;
+pub mod settings;
+pub mod tasks;
 
 pub fn xml_syntax_escaped<T: Into<String>>(text: T) -> String {
+    // `&` 必须最先替换，否则会重复转义其它实体
     text.into()
+        .replace("&", "&amp;")
         .replace("<", "&lt;")
         .replace(">", "&gt;")
-        .replace("&", "&amp;")
         .replace("'", "&apos;")
         .replace("\"", "&quot;")
         .to_string()
@@ -28,6 +38,10 @@ pub fn xml_syntax_escaped<T: Into<String>>(text: T) -> String {
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 pub fn create_spinner(message: &str) -> ProgressBar {
+    // JSON 模式下不显示动画
+    if output::is_json() {
+        return ProgressBar::hidden();
+    }
     let pb = ProgressBar::new_spinner();
     pb.enable_steady_tick(50);
     pb.set_style(
@@ -40,6 +54,12 @@ pub fn create_spinner(message: &str) -> ProgressBar {
 }
 
 pub fn read_input_as_string(msg: &str) -> Result<String> {
+    if output::is_json() {
+        return Err(err_msg(format!(
+            "Interactive input is not available in JSON mode: {}",
+            msg.trim()
+        )));
+    }
     let mut s = String::new();
     print!("{}", msg);
     stdout().flush()?;
@@ -47,42 +67,244 @@ pub fn read_input_as_string(msg: &str) -> Result<String> {
     Ok(s.trim().to_string())
 }
 
+// 无法确定 XDG 目录时使用的输出及缓存目录（相对于当前目录）
 pub static OUTPUT_DIR: &'static str = "_output";
 pub static CACHE_DIR: &'static str = "_cache";
+pub static DATA_DIR: &'static str = "_data";
 
-pub fn cache_to(base_dir: &str, name: &str, bytes: &Vec<u8>) -> Result<()> {
-    let mut base_path = PathBuf::from(CACHE_DIR);
-    base_path.push(base_dir);
-    save_to(base_path, name, bytes)
+pub fn cache_to(cache_dir: &Path, base_dir: &str, name: &str, bytes: &Vec<u8>) -> Result<()> {
+    save_to(cache_dir.join(base_dir), name, bytes)
 }
 
 pub fn save_to(base_path: PathBuf, name: &str, bytes: &Vec<u8>) -> Result<()> {
     fs::create_dir_all(&base_path)?;
     let mut fpath = base_path.clone();
     fpath.push(name);
-    let mut file = File::create(fpath)?;
+    // 先写入临时文件再重命名，中断时不会留下不完整的文件
+    let mut part_path = base_path.clone();
+    part_path.push(format!("{}.part", name));
+    let mut file = File::create(&part_path)?;
     file.write_all(bytes)?;
+    fs::rename(part_path, fpath)?;
     Ok(())
 }
 
-pub fn get_resp(url: &str, headers: &HashMap<String, String>) -> Result<Response> {
-    let mut header_map = HeaderMap::new();
-    for (key, value) in headers {
-        header_map.insert(
-            HeaderName::from_bytes(key.as_bytes())?,
-            HeaderValue::from_str(&value)?,
-        );
+/// 缓存目录中的 metadata.json
+#[derive(Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(flatten)]
+    pub chapter: Chapter,
+    /// 已下载完成的页码（从 1 开始）
+    #[serde(default)]
+    pub finished: Vec<usize>,
+    /// 尚未下载完成的页码
+    #[serde(default)]
+    pub pending: Vec<usize>,
+    #[serde(default)]
+    pub origin: Origin,
+}
+
+impl Metadata {
+    /// 章节的页数，下载未完成时 chapter.pages 中只有已完成的页面
+    pub fn total(&self) -> usize {
+        (self.finished.len() + self.pending.len()).max(self.chapter.pages.len())
+    }
+}
+
+/// 章节的来源，直接下载阅读页时漫画信息未知
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Origin {
+    pub comic_title: Option<String>,
+    pub comic_url: Option<String>,
+    /// 章节在漫画中的序号（从 1 开始）
+    pub index: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct MetadataRef<'a> {
+    #[serde(flatten)]
+    chapter: &'a Chapter,
+    finished: Vec<usize>,
+    pending: Vec<usize>,
+    origin: &'a Origin,
+}
+
+/// 写入章节的 metadata.json，total 为章节的页数，pending 为尚未下载完成的页码
+pub fn cache_metadata(
+    cache_dir: &Path,
+    base_dir: &str,
+    chapter: &Chapter,
+    origin: &Origin,
+    total: usize,
+    pending: &[usize],
+) -> Result<()> {
+    let metadata = MetadataRef {
+        chapter,
+        origin,
+        finished: (1..=total).filter(|n| !pending.contains(n)).collect(),
+        pending: pending.to_vec(),
+    };
+    let json = serde_json::to_string(&metadata)?;
+    cache_to(
+        cache_dir,
+        base_dir,
+        "metadata.json",
+        &json.as_bytes().to_vec(),
+    )
+}
+
+/// 链接的主机名（平台域名）
+pub fn platform(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_string()))
+        .unwrap_or_default()
+}
+
+// 直接下载阅读页（不经过漫画主页）时使用的漫画标识
+const UNKNOWN_COMIC: &'static str = "_";
+// 链接标识的最大长度，超出时截断并追加哈希
+const MAX_ID_BYTES: usize = 96;
+
+/// 章节在缓存中的相对路径 `<domain>/<comic-id>/<chapter-id>`，各部分均取自链接，
+/// 不同漫画的同名章节不会互相覆盖。漫画未知时 comic-id 为 `_`
+pub fn chapter_cache_dir(comic_url: Option<&str>, chapter_url: &str) -> Result<String> {
+    let url = Url::parse(chapter_url)?;
+    let domain = fsname::sanitize(url.host_str().unwrap_or(UNKNOWN_COMIC));
+    let comic_id = match comic_url {
+        Some(comic_url) => url_id(&Url::parse(comic_url)?),
+        None => UNKNOWN_COMIC.to_string(),
+    };
+    Ok(format!("{}/{}/{}", domain, comic_id, url_id(&url)))
+}
+
+/// 将之前直接下载（漫画未知）的阅读页缓存归入所属的漫画，目标已存在时不移动
+pub fn adopt_cached_chapter(cache_dir: &Path, comic_url: &str, chapter_url: &str) -> Result<()> {
+    let from = cache_dir.join(chapter_cache_dir(None, chapter_url)?);
+    let to = cache_dir.join(chapter_cache_dir(Some(comic_url), chapter_url)?);
+    if from.is_dir() && !to.exists() {
+        move_dir(&from, &to)?;
+    }
+    Ok(())
+}
+
+/// 由链接的路径和查询参数生成稳定的标识（eg: `/m136026/` -> `m136026`）
+pub fn url_id(url: &Url) -> String {
+    let mut id = url.path().trim_matches('/').to_string();
+    for ext in &[".html", ".htm", ".shtml", ".php"] {
+        if id.ends_with(ext) {
+            id.truncate(id.len() - ext.len());
+            break;
+        }
+    }
+    let mut id = id.replace('/', "-");
+    if let Some(query) = url.query() {
+        id.push('_');
+        id.push_str(query);
+    }
+    if id.is_empty() {
+        id.push_str("index");
+    }
+    if id.len() > MAX_ID_BYTES {
+        let mut end = MAX_ID_BYTES;
+        while !id.is_char_boundary(end) {
+            end -= 1;
+        }
+        id = format!("{}-{:016x}", &id[..end], fnv1a(url.as_str().as_bytes()));
+    }
+    fsname::sanitize(&id)
+}
+
+// FNV-1a 哈希，结果不随 Rust 版本变化
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// 将旧版以章节标题平铺的缓存（`<cache>/<title>/`）迁移到新的目录结构，
+/// 当前目录下的 `_cache` 同样会被迁移。无法识别的目录保持不变，返回迁移前后的路径
+pub fn migrate_cache(cache_dir: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut sources = vec![cache_dir.to_path_buf()];
+    let legacy = PathBuf::from(CACHE_DIR);
+    if legacy.is_dir() && fs::canonicalize(&legacy).ok() != fs::canonicalize(cache_dir).ok() {
+        sources.push(legacy);
+    }
+    let mut migrated = vec![];
+    for source in sources {
+        if !source.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&source)? {
+            let path = entry?.path();
+            let metadata = match fs::read_to_string(path.join("metadata.json")) {
+                Ok(json) => match serde_json::from_str::<Metadata>(&json) {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                },
+                Err(_) => continue,
+            };
+            let base_dir = match chapter_cache_dir(None, &metadata.chapter.url) {
+                Ok(base_dir) => base_dir,
+                Err(_) => continue,
+            };
+            // 新位置已有缓存时保留旧目录，不覆盖
+            if cache_dir.join(&base_dir).exists() {
+                continue;
+            }
+            move_dir(&path, &cache_dir.join(&base_dir))?;
+            migrated.push((path, base_dir));
+        }
+    }
+    Ok(migrated)
+}
+
+/// 移动章节缓存目录，无法直接重命名（跨文件系统）时复制后删除
+fn move_dir(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    fs::remove_dir_all(from)?;
+    Ok(())
+}
+
+/// 已缓存且完整可用的页面图片，返回其 MIME 类型
+pub fn cached_page(cache_dir: &Path, base_dir: &str, fname: &str) -> Option<&'static str> {
+    let bytes = fs::read(cache_dir.join(base_dir).join(fname)).ok()?;
+    image_mime(&bytes)
+}
+
+/// 根据文件头识别图片的 MIME 类型
+pub fn image_mime(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(b"GIF8") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if bytes.starts_with(b"BM") {
+        Some("image/bmp")
+    } else {
+        None
     }
-    let client = Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()?
-        .get(url)
-        .headers(header_map);
-    Ok(client.send()?)
 }
 
 lazy_static! {
     static ref SELECT_SEPARATOR_RE: Regex = Regex::new("(,|，)").unwrap();
+    // parse_select_rule 支持的单项规则：n、s-e 和 ^n
+    static ref SELECT_TOKEN_RE: Regex = Regex::new(r"^(\d+|\d+-\d+|\^\d+)$").unwrap();
 }
 
 pub fn parse_select_rule(input_s: &str) -> Result<Vec<usize>> {
@@ -121,3 +343,214 @@ pub fn parse_select_rule(input_s: &str) -> Result<Vec<usize>> {
         .map(|i| *i as usize)
         .collect())
 }
+
+/// 按规则从 total 个章节中选择，除 parse_select_rule 的语法外还支持
+/// `all`（全部）、`latest`（最新一话）和 `last:n`（最新 n 话）。
+/// 存在无法识别的规则或没有选中任何章节时返回错误
+pub fn select_chapters(rule: &str, total: usize) -> Result<Vec<usize>> {
+    let mut expanded = vec![];
+    for t in SELECT_SEPARATOR_RE.split(rule).map(|s| s.trim()) {
+        let numbers: Vec<usize> = match t {
+            "" => continue,
+            "all" => (1..=total).collect(),
+            "latest" => (total..=total).filter(|n| *n > 0).collect(),
+            _ if t.starts_with("last:") => {
+                let n = t["last:".len()..]
+                    .parse::<usize>()
+                    .map_err(|_| err_msg(format!("Invalid chapter rule: {}", t)))?;
+                (total.saturating_sub(n) + 1..=total).collect()
+            }
+            _ if SELECT_TOKEN_RE.is_match(t) => {
+                expanded.push(t.to_string());
+                continue;
+            }
+            _ => return Err(err_msg(format!("Invalid chapter rule: {}", t))),
+        };
+        expanded.extend(numbers.iter().map(|n| n.to_string()));
+    }
+    let selects = parse_select_rule(&expanded.join(","))?;
+    if let Some(n) = selects.iter().find(|n| **n == 0 || **n > total) {
+        return Err(err_msg(format!(
+            "Chapter number {} is out of range (1-{})",
+            n, total
+        )));
+    }
+    if selects.is_empty() {
+        return Err(err_msg(format!("No chapters selected by rule: {}", rule)));
+    }
+    Ok(selects)
+}
+
+/// 从图片头部读取宽高（支持 JPEG/PNG/GIF/WebP）
+pub fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |i: usize| ((bytes[i] as u32) << 8) | bytes[i + 1] as u32;
+    let le16 = |i: usize| bytes[i] as u32 | ((bytes[i + 1] as u32) << 8);
+    let le24 = |i: usize| le16(i) | ((bytes[i + 2] as u32) << 16);
+    if bytes.len() >= 24 && bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let w = (be16(16) << 16) | be16(18);
+        let h = (be16(20) << 16) | be16(22);
+        return Some((w, h));
+    }
+    if bytes.len() >= 10 && bytes.starts_with(b"GIF8") {
+        return Some((le16(6), le16(8)));
+    }
+    if bytes.len() >= 30 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        return match &bytes[12..16] {
+            b"VP8 " => Some((le16(26) & 0x3fff, le16(28) & 0x3fff)),
+            b"VP8L" => {
+                let bits = le16(21) | (le16(23) << 16);
+                Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+            }
+            b"VP8X" => Some((le24(24) + 1, le24(27) + 1)),
+            _ => None,
+        };
+    }
+    jpeg_frame(bytes).map(|(w, h, _)| (w, h))
+}
+
+/// 读取 JPEG 帧头中的宽高及颜色分量数
+pub fn jpeg_frame(bytes: &[u8]) -> Option<(u32, u32, u8)> {
+    if !bytes.starts_with(b"\xff\xd8") {
+        return None;
+    }
+    let be16 = |i: usize| ((bytes[i] as u32) << 8) | bytes[i + 1] as u32;
+    let mut i = 2;
+    while i + 9 < bytes.len() {
+        if bytes[i] != 0xff {
+            return None;
+        }
+        let marker = bytes[i + 1];
+        // 填充字节
+        if marker == 0xff {
+            i += 1;
+            continue;
+        }
+        // SOFn（排除 DHT/JPG/DAC）
+        if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+            return Some((be16(i + 7), be16(i + 5), bytes[i + 9]));
+        }
+        i += 2 + be16(i + 2) as usize;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xml_syntax_escaped() {
+        assert_eq!(
+            xml_syntax_escaped("<海贼王> & \"One Piece\" 'x'"),
+            "&lt;海贼王&gt; &amp; &quot;One Piece&quot; &apos;x&apos;"
+        );
+        assert_eq!(xml_syntax_escaped("&lt;"), "&amp;lt;");
+    }
+
+    #[test]
+    fn test_select_chapters() {
+        assert_eq!(select_chapters("1,3-5,^4", 10).unwrap(), vec![1, 3, 5]);
+        assert_eq!(select_chapters("all", 3).unwrap(), vec![1, 2, 3]);
+        assert_eq!(select_chapters("latest", 3).unwrap(), vec![3]);
+        assert_eq!(select_chapters("last:2", 5).unwrap(), vec![4, 5]);
+        // 超出章节数量时为全部章节
+        assert_eq!(select_chapters("last:9", 3).unwrap(), vec![1, 2, 3]);
+        assert_eq!(select_chapters("1，2,", 3).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_select_chapters_errors() {
+        assert!(select_chapters("last:0", 3).is_err());
+        assert!(select_chapters("latest", 0).is_err());
+        assert!(select_chapters("lastest", 3).is_err());
+        assert!(select_chapters("last3", 3).is_err());
+        assert!(select_chapters("last:x", 3).is_err());
+        assert!(select_chapters("4", 3).is_err());
+        assert!(select_chapters("", 3).is_err());
+    }
+
+    #[test]
+    fn test_url_id() {
+        let id = |url: &str| url_id(&Url::parse(url).unwrap());
+        assert_eq!(id("https://www.dm5.com/m136026/"), "m136026");
+        assert_eq!(id("https://www.dm5.com/m1029843.html"), "m1029843");
+        assert_eq!(id("https://example.com/comic/12/34/"), "comic-12-34");
+        assert_eq!(id("https://example.com/read.php?id=5"), "read_id=5");
+        assert_eq!(id("https://example.com/"), "index");
+    }
+
+    #[test]
+    fn test_url_id_too_long() {
+        let long = format!("https://example.com/{}", "x".repeat(200));
+        let id = url_id(&Url::parse(&long).unwrap());
+        assert_eq!(id.len(), MAX_ID_BYTES + 17);
+        assert!(id.starts_with(&"x".repeat(MAX_ID_BYTES)));
+        // 截断后仍能区分不同的链接
+        let other = url_id(&Url::parse(&format!("{}y", long)).unwrap());
+        assert_ne!(id, other);
+    }
+
+    #[test]
+    fn test_chapter_cache_dir() {
+        let chapter = "https://www.dm5.com/m1029843/";
+        assert_eq!(
+            chapter_cache_dir(None, chapter).unwrap(),
+            "www.dm5.com/_/m1029843"
+        );
+        assert_eq!(
+            chapter_cache_dir(Some("https://www.dm5.com/m136026/"), chapter).unwrap(),
+            "www.dm5.com/m136026/m1029843"
+        );
+        assert!(chapter_cache_dir(None, "not a url").is_err());
+    }
+
+    /// RIFF 容器中的 WebP 头部，补齐到可以读取尺寸的长度
+    fn webp(chunk: &[u8], payload: &[u8]) -> Vec<u8> {
+        let parts: [&[u8]; 6] = [b"RIFF", &[0; 4], b"WEBP", chunk, &[0; 4], payload];
+        let mut bytes = parts.concat();
+        bytes.resize(30, 0);
+        bytes
+    }
+
+    #[test]
+    fn test_image_dimensions() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\x03\x20\0\0\x04\xb0\x08\x02\0\0\0";
+        assert_eq!(image_dimensions(png), Some((800, 1200)));
+        assert_eq!(image_dimensions(&png[..20]), None);
+        assert_eq!(
+            image_dimensions(b"GIF89a\x20\x03\xb0\x04\0\0"),
+            Some((800, 1200))
+        );
+
+        let vp8 = webp(b"VP8 ", b"\0\0\0\x9d\x01\x2a\x20\x03\xb0\x04");
+        assert_eq!(image_dimensions(&vp8), Some((800, 1200)));
+        let bits: u32 = 799 | (1199 << 14);
+        let mut payload = vec![0x2f];
+        payload.extend_from_slice(&bits.to_le_bytes());
+        let vp8l = webp(b"VP8L", &payload);
+        assert_eq!(image_dimensions(&vp8l), Some((800, 1200)));
+        let vp8x = webp(b"VP8X", b"\0\0\0\0\x1f\x03\0\xaf\x04\0");
+        assert_eq!(image_dimensions(&vp8x), Some((800, 1200)));
+        assert_eq!(image_dimensions(&webp(b"ALPH", b"")), None);
+        assert_eq!(image_dimensions(b"not an image"), None);
+    }
+
+    #[test]
+    fn test_jpeg_frame() {
+        // APP0 之后是 SOF2（渐进式），3 通道 800x1200
+        let mut jpeg = b"\xff\xd8\xff\xe0\0\x04\0\0".to_vec();
+        // 段之间的填充字节
+        jpeg.extend_from_slice(b"\xff");
+        jpeg.extend_from_slice(b"\xff\xc2\0\x11\x08\x04\xb0\x03\x20\x03");
+        jpeg.resize(40, 0);
+        assert_eq!(jpeg_frame(&jpeg), Some((800, 1200, 3)));
+        assert_eq!(image_dimensions(&jpeg), Some((800, 1200)));
+
+        // DHT（0xc4）不是帧头
+        let mut dht = b"\xff\xd8\xff\xc4\0\x04\0\0\xff\xc0\0\x0b\x08\0\x03\0\x04\x01".to_vec();
+        dht.resize(40, 0);
+        assert_eq!(jpeg_frame(&dht), Some((4, 3, 1)));
+        assert_eq!(jpeg_frame(b"\xff\xd8\0\0\0\0\0\0\0\0\0\0"), None);
+        assert_eq!(jpeg_frame(b"\x89PNG"), None);
+    }
+}
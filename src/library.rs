@@ -0,0 +1,173 @@
+use crate::history;
+use crate::save_to;
+use chrono::offset::Utc;
+use mikack::error::*;
+use mikack::models::Comic;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const LIBRARY_FILE: &'static str = "library.json";
+
+/// 订阅的漫画列表，保存在数据目录的 library.json 中
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Library {
+    pub comics: Vec<Subscription>,
+}
+
+/// 一部订阅的漫画
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub title: String,
+    pub url: String,
+    /// 已下载（或订阅时选择跳过）的章节链接
+    #[serde(default)]
+    pub downloaded: Vec<String>,
+    pub followed_at: String,
+    #[serde(default)]
+    pub checked_at: Option<String>,
+}
+
+impl Library {
+    pub fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join(LIBRARY_FILE)
+    }
+
+    /// 读取订阅列表，文件不存在时为空
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = Self::path(data_dir);
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let json = fs::read_to_string(&path)?;
+        serde_json::from_str(&json)
+            .map_err(|e| err_msg(format!("Invalid library {}: {}", path.display(), e)))
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        save_to(
+            data_dir.to_path_buf(),
+            LIBRARY_FILE,
+            &json.as_bytes().to_vec(),
+        )
+    }
+
+    /// 订阅漫画（需已获取章节列表），skip_existing 时现有章节不再视为新章节。
+    /// 已订阅时返回 false
+    pub fn follow(&mut self, comic: &Comic, skip_existing: bool) -> bool {
+        if self.get(&comic.url).is_some() {
+            return false;
+        }
+        let downloaded = if skip_existing {
+            comic.chapters.iter().map(|c| c.url.clone()).collect()
+        } else {
+            vec![]
+        };
+        self.comics.push(Subscription {
+            title: comic.title.clone(),
+            url: comic.url.clone(),
+            downloaded,
+            followed_at: Utc::now().to_rfc3339(),
+            checked_at: None,
+        });
+        true
+    }
+
+    /// 取消订阅，未订阅时返回 false
+    pub fn unfollow(&mut self, url: &str) -> bool {
+        let len = self.comics.len();
+        self.comics.retain(|comic| comic.url != url);
+        self.comics.len() != len
+    }
+
+    pub fn get(&self, url: &str) -> Option<&Subscription> {
+        self.comics.iter().find(|comic| comic.url == url)
+    }
+
+    pub fn get_mut(&mut self, url: &str) -> Option<&mut Subscription> {
+        self.comics.iter_mut().find(|comic| comic.url == url)
+    }
+}
+
+impl Subscription {
+    /// 尚未下载的章节序号（从 1 开始），下载历史中已有的章节同样视为已下载
+    pub fn new_chapters(&self, comic: &Comic, history: &[history::Entry]) -> Vec<usize> {
+        comic
+            .chapters
+            .iter()
+            .enumerate()
+            .filter(|(_, chapter)| {
+                !self.downloaded.contains(&chapter.url)
+                    && !history.iter().any(|entry| entry.chapter_url == chapter.url)
+            })
+            .map(|(i, _)| i + 1)
+            .collect()
+    }
+
+    pub fn mark_downloaded(&mut self, chapter_url: &str) {
+        if !self.downloaded.iter().any(|url| url == chapter_url) {
+            self.downloaded.push(chapter_url.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Origin;
+    use mikack::models::Chapter;
+
+    fn comic(chapters: usize) -> Comic {
+        let mut comic = Comic::new("One Piece", "https://example.com/comic/1");
+        for i in 1..=chapters {
+            comic.chapters.push(Chapter::new(
+                &format!("第{}话", i),
+                &format!("https://example.com/chapter/{}", i),
+                0,
+            ));
+        }
+        comic
+    }
+
+    #[test]
+    fn test_new_chapters() {
+        let mut library = Library::default();
+        let mut comic = comic(3);
+        assert!(library.follow(&comic, false));
+        let subscription = library.get_mut(&comic.url).unwrap();
+        assert_eq!(subscription.new_chapters(&comic, &[]), vec![1, 2, 3]);
+
+        subscription.mark_downloaded("https://example.com/chapter/1");
+        subscription.mark_downloaded("https://example.com/chapter/1");
+        assert_eq!(subscription.downloaded.len(), 1);
+        // 下载历史中的章节同样不是新章节
+        let history = [history::Entry::new(&comic.chapters[2], &Origin::default())];
+        assert_eq!(subscription.new_chapters(&comic, &history), vec![2]);
+
+        // 更新后新增的章节
+        comic
+            .chapters
+            .push(Chapter::new("第4话", "https://example.com/chapter/4", 0));
+        assert_eq!(subscription.new_chapters(&comic, &history), vec![2, 4]);
+    }
+
+    #[test]
+    fn test_follow_skip_existing() {
+        let mut library = Library::default();
+        let mut comic = comic(2);
+        assert!(library.follow(&comic, true));
+        assert!(!library.follow(&comic, false));
+        assert_eq!(library.comics.len(), 1);
+        let subscription = library.get(&comic.url).unwrap();
+        assert!(subscription.new_chapters(&comic, &[]).is_empty());
+        comic
+            .chapters
+            .push(Chapter::new("第3话", "https://example.com/chapter/3", 0));
+        assert_eq!(subscription.new_chapters(&comic, &[]), vec![3]);
+
+        assert!(library.unfollow(&comic.url));
+        assert!(!library.unfollow(&comic.url));
+        assert!(library.comics.is_empty());
+    }
+}
@@ -0,0 +1,532 @@
+use crate::history::{self, History};
+use crate::settings::Settings;
+use crate::{exporters, fnv1a};
+use chrono::{offset::Utc, DateTime};
+use image::ImageOutputFormat;
+use mikack::error::*;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tera::{Context, Tera};
+
+pub const NAVIGATION: &'static str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+pub const ACQUISITION: &'static str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+// 归类不到漫画的文件
+const UNKNOWN_COMIC: &'static str = "其它";
+const THUMBNAIL_SIZE: (u32, u32) = (240, 320);
+
+/// 输出目录中导出的一本书
+#[derive(Debug, Clone)]
+pub struct Book {
+    /// 由相对路径计算的标识
+    pub id: String,
+    /// 相对于输出目录的路径，以 `/` 分隔
+    pub path: String,
+    pub title: String,
+    pub comic: String,
+    pub comic_url: Option<String>,
+    /// 章节在漫画中的序号，合卷时为第一个章节的序号
+    pub index: Option<usize>,
+    pub mime: &'static str,
+    pub size: u64,
+    /// 文件的修改时间（RFC 3339）
+    pub updated: String,
+    /// 第一个章节缓存的第一页及其 MIME 类型
+    pub cover: Option<(PathBuf, String)>,
+}
+
+impl Book {
+    fn comic_id(&self) -> String {
+        id(self.comic_url.as_ref().unwrap_or(&self.comic))
+    }
+
+    fn matches(&self, keywords: &str) -> bool {
+        let keywords = keywords.to_lowercase();
+        self.title.to_lowercase().contains(&keywords)
+            || self.comic.to_lowercase().contains(&keywords)
+    }
+}
+
+/// 输出目录中全部的 EPUB、CBZ 和 PDF 文件。
+/// 漫画及章节信息来自下载历史，没有记录的文件按所在目录归类
+pub struct Catalog {
+    pub books: Vec<Book>,
+}
+
+impl Catalog {
+    pub fn scan(settings: &Settings) -> Result<Self> {
+        let mut files = vec![];
+        collect_books(&settings.output_dir, &mut files);
+        // 合卷的文件有多条记录，重复导出的章节只保留一条
+        let mut exports: HashMap<PathBuf, Vec<history::Entry>> = HashMap::new();
+        for entry in History::open(&settings.data_dir).entries()? {
+            if let Some(output) = &entry.output {
+                let chapters = exports.entry(PathBuf::from(output)).or_default();
+                if !chapters.iter().any(|c| c.chapter_url == entry.chapter_url) {
+                    chapters.push(entry);
+                }
+            }
+        }
+        // 缓存中的章节序号及第一页
+        let cached = exporters::cached_chapters(&settings.cache_dir)?
+            .into_iter()
+            .map(|(base_dir, metadata)| {
+                let cover = metadata.chapter.pages.first().map(|page| {
+                    (
+                        settings.cache_dir.join(&base_dir).join(&page.fname),
+                        page.fmime.clone(),
+                    )
+                });
+                (metadata.chapter.url.clone(), (metadata.origin.index, cover))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut books = vec![];
+        for (path, mime) in files {
+            let relative = match path.strip_prefix(&settings.output_dir) {
+                Ok(relative) => relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy().to_string())
+                    .collect::<Vec<_>>()
+                    .join("/"),
+                Err(_) => continue,
+            };
+            let metadata = fs::metadata(&path)?;
+            let updated = metadata
+                .modified()
+                .map(|time| DateTime::<Utc>::from(time))
+                .unwrap_or_else(|_| Utc::now())
+                .to_rfc3339();
+            let stem = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let chapters = exports.get(&path).map(|c| c.as_slice()).unwrap_or(&[]);
+            let title = match chapters {
+                [chapter] => chapter.title.clone(),
+                _ => stem,
+            };
+            let first = chapters.first();
+            let (index, cover) = first
+                .and_then(|chapter| cached.get(&chapter.chapter_url))
+                .cloned()
+                .unwrap_or_default();
+            let comic = match first.and_then(|chapter| chapter.comic_title.clone()) {
+                Some(comic) => comic,
+                None => match relative.rsplitn(2, '/').nth(1) {
+                    Some(dir) => dir.to_string(),
+                    None => UNKNOWN_COMIC.to_string(),
+                },
+            };
+            books.push(Book {
+                id: id(&relative),
+                path: relative,
+                title,
+                comic,
+                comic_url: first.and_then(|chapter| chapter.comic_url.clone()),
+                index,
+                mime,
+                size: metadata.len(),
+                updated,
+                cover: cover.filter(|(path, _)| path.is_file()),
+            });
+        }
+        books.sort_by(|a, b| (&a.comic, a.index, &a.title).cmp(&(&b.comic, b.index, &b.title)));
+        Ok(Self { books })
+    }
+
+    pub fn book(&self, id: &str) -> Option<&Book> {
+        self.books.iter().find(|book| book.id == id)
+    }
+
+    /// 按漫画分组，返回漫画标识、名称及其图书
+    pub fn comics(&self) -> Vec<(String, String, Vec<&Book>)> {
+        let mut comics: Vec<(String, String, Vec<&Book>)> = vec![];
+        for book in &self.books {
+            let comic_id = book.comic_id();
+            match comics.iter_mut().find(|(id, _, _)| *id == comic_id) {
+                Some((_, _, books)) => books.push(book),
+                None => comics.push((comic_id, book.comic.clone(), vec![book])),
+            }
+        }
+        comics
+    }
+
+    /// 根目录（导航 Feed）
+    pub fn root_feed(&self, base: &str) -> Result<String> {
+        let now = Utc::now().to_rfc3339();
+        let entries = vec![
+            Entry::navigation("all", "全部", &now, "/opds/all", ACQUISITION),
+            Entry::navigation("comics", "按漫画", &now, "/opds/comics", NAVIGATION),
+        ];
+        render_feed(base, "root", "mikack-cli", "/opds", "navigation", &entries)
+    }
+
+    /// 漫画列表（导航 Feed）
+    pub fn comics_feed(&self, base: &str) -> Result<String> {
+        let entries = self
+            .comics()
+            .into_iter()
+            .map(|(comic_id, comic, books)| {
+                let updated = books.iter().map(|book| &book.updated).max().unwrap();
+                let mut entry = Entry::navigation(
+                    &format!("comic:{}", comic_id),
+                    &comic,
+                    updated,
+                    &format!("/opds/comics/{}", comic_id),
+                    ACQUISITION,
+                );
+                entry.content = Some(format!("{} 本", books.len()));
+                entry
+            })
+            .collect::<Vec<_>>();
+        render_feed(
+            base,
+            "comics",
+            "按漫画",
+            "/opds/comics",
+            "navigation",
+            &entries,
+        )
+    }
+
+    /// 一部漫画的图书（获取 Feed），漫画不存在时为空
+    pub fn comic_feed(&self, base: &str, comic_id: &str) -> Result<Option<String>> {
+        let (_, comic, books) = match self.comics().into_iter().find(|(id, _, _)| *id == comic_id) {
+            Some(comic) => comic,
+            None => return Ok(None),
+        };
+        let entries = books.into_iter().map(Entry::book).collect::<Vec<_>>();
+        let href = format!("/opds/comics/{}", comic_id);
+        Ok(Some(render_feed(
+            base,
+            &format!("comic:{}", comic_id),
+            &comic,
+            &href,
+            "acquisition",
+            &entries,
+        )?))
+    }
+
+    /// 全部图书（获取 Feed），最近导出的在前
+    pub fn all_feed(&self, base: &str) -> Result<String> {
+        let mut books = self.books.iter().collect::<Vec<_>>();
+        books.sort_by(|a, b| b.updated.cmp(&a.updated));
+        let entries = books.into_iter().map(Entry::book).collect::<Vec<_>>();
+        render_feed(base, "all", "全部", "/opds/all", "acquisition", &entries)
+    }
+
+    /// 按书名或漫画名搜索（获取 Feed）
+    pub fn search_feed(&self, base: &str, keywords: &str) -> Result<String> {
+        let entries = self
+            .books
+            .iter()
+            .filter(|book| book.matches(keywords))
+            .map(Entry::book)
+            .collect::<Vec<_>>();
+        let href = format!(
+            "/opds/search?q={}",
+            utf8_percent_encode(keywords, NON_ALPHANUMERIC)
+        );
+        render_feed(
+            base,
+            &format!("search:{}", keywords),
+            &format!("搜索：{}", keywords),
+            &href,
+            "acquisition",
+            &entries,
+        )
+    }
+}
+
+/// 搜索描述（OpenSearch）
+pub fn opensearch(base: &str) -> Result<String> {
+    let template = include_str!("../template/opds/opensearch.xml");
+    let mut ctx = Context::new();
+    ctx.insert("base", base);
+    Ok(Tera::one_off(&template, &ctx, true)?)
+}
+
+/// 缩小后的 JPEG 封面
+pub fn thumbnail(book: &Book) -> Result<Option<Vec<u8>>> {
+    let path = match &book.cover {
+        Some((path, _)) => path,
+        None => return Ok(None),
+    };
+    let (width, height) = THUMBNAIL_SIZE;
+    let thumbnail = image::load_from_memory(&fs::read(path)?)?.thumbnail(width, height);
+    let mut bytes = vec![];
+    thumbnail.write_to(&mut bytes, ImageOutputFormat::Jpeg(85))?;
+    Ok(Some(bytes))
+}
+
+#[derive(Serialize)]
+struct Link {
+    rel: &'static str,
+    href: String,
+    mime: String,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    id: String,
+    title: String,
+    updated: String,
+    author: Option<String>,
+    content: Option<String>,
+    links: Vec<Link>,
+}
+
+impl Entry {
+    fn navigation(id: &str, title: &str, updated: &str, href: &str, kind: &str) -> Self {
+        Self {
+            id: format!("urn:mikack-cli:{}", id),
+            title: title.to_string(),
+            updated: updated.to_string(),
+            author: None,
+            content: None,
+            links: vec![Link {
+                rel: "subsection",
+                href: href.to_string(),
+                mime: kind.to_string(),
+            }],
+        }
+    }
+
+    fn book(book: &Book) -> Self {
+        let href = book
+            .path
+            .split('/')
+            .map(|segment| utf8_percent_encode(segment, NON_ALPHANUMERIC).to_string())
+            .collect::<Vec<_>>()
+            .join("/");
+        let mut links = vec![Link {
+            rel: "http://opds-spec.org/acquisition",
+            href: format!("/files/{}", href),
+            mime: book.mime.to_string(),
+        }];
+        if let Some((_, mime)) = &book.cover {
+            links.push(Link {
+                rel: "http://opds-spec.org/image",
+                href: format!("/opds/covers/{}", book.id),
+                mime: mime.clone(),
+            });
+            links.push(Link {
+                rel: "http://opds-spec.org/image/thumbnail",
+                href: format!("/opds/thumbnails/{}", book.id),
+                mime: "image/jpeg".to_string(),
+            });
+        }
+        Self {
+            id: format!("urn:mikack-cli:book:{}", book.id),
+            title: book.title.clone(),
+            updated: book.updated.clone(),
+            author: Some(book.comic.clone()),
+            content: Some(format!("{}（{}）", book.path, human_size(book.size))),
+            links,
+        }
+    }
+}
+
+fn render_feed(
+    base: &str,
+    id: &str,
+    title: &str,
+    href: &str,
+    kind: &str,
+    entries: &[Entry],
+) -> Result<String> {
+    let template = include_str!("../template/opds/feed.xml");
+    let mut ctx = Context::new();
+    ctx.insert("base", base);
+    ctx.insert("id", &format!("urn:mikack-cli:{}", id));
+    ctx.insert("title", title);
+    ctx.insert("updated", &Utc::now().to_rfc3339());
+    ctx.insert("href", href);
+    ctx.insert("kind", kind);
+    ctx.insert("entries", entries);
+    Ok(Tera::one_off(&template, &ctx, true)?)
+}
+
+fn id(key: &str) -> String {
+    format!("{:016x}", fnv1a(key.as_bytes()))
+}
+
+fn human_size(size: u64) -> String {
+    match size {
+        size if size >= 1 << 20 => format!("{:.1} MB", size as f64 / (1 << 20) as f64),
+        size if size >= 1 << 10 => format!("{:.1} KB", size as f64 / (1 << 10) as f64),
+        size => format!("{} B", size),
+    }
+}
+
+/// 输出目录中可以通过 OPDS 提供的文件及其 MIME 类型
+fn collect_books(dir: &Path, files: &mut Vec<(PathBuf, &'static str)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_books(&path, files);
+            continue;
+        }
+        let mime = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("epub") => "application/epub+zip",
+            Some("cbz") => "application/vnd.comicbook+zip",
+            Some("pdf") => "application/pdf",
+            _ => continue,
+        };
+        files.push((path, mime));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 简单的格式良好性检查：标签成对嵌套、属性值带引号、`&` 只出现在实体中，
+    /// 返回根元素的名称
+    fn check_xml(xml: &str) -> String {
+        let check_text = |text: &str| {
+            assert!(!text.contains('<'), "unescaped < in {:?}", text);
+            for (i, _) in text.match_indices('&') {
+                let entity = &text[i..text[i..].find(';').map(|end| i + end + 1).unwrap()];
+                assert!(
+                    ["&amp;", "&lt;", "&gt;", "&quot;", "&apos;"].contains(&entity)
+                        || entity.starts_with("&#"),
+                    "invalid entity {:?}",
+                    entity
+                );
+            }
+        };
+        let mut stack: Vec<String> = vec![];
+        let mut root = None;
+        let mut rest = xml.trim_start();
+        assert!(rest.starts_with("<?xml "));
+        rest = &rest[rest.find("?>").unwrap() + 2..];
+        while let Some(start) = rest.find('<') {
+            check_text(&rest[..start]);
+            if stack.is_empty() {
+                assert!(rest[..start].trim().is_empty(), "text outside the root");
+            }
+            let end = start + rest[start..].find('>').expect("unclosed tag");
+            let tag = &rest[start + 1..end];
+            rest = &rest[end + 1..];
+            if let Some(name) = tag.strip_prefix('/') {
+                assert_eq!(stack.pop().as_deref(), Some(name.trim()));
+                continue;
+            }
+            let self_closing = tag.ends_with('/');
+            let tag = tag.trim_end_matches('/');
+            let name = tag.split_whitespace().next().unwrap().to_string();
+            // 属性：name="value"
+            let mut attrs = tag[name.len()..].trim();
+            while !attrs.is_empty() {
+                let eq = attrs.find("=\"").expect("unquoted attribute");
+                assert!(!attrs[..eq].trim().contains(char::is_whitespace));
+                let value_end = eq + 2 + attrs[eq + 2..].find('"').unwrap();
+                check_text(&attrs[eq + 2..value_end]);
+                attrs = attrs[value_end + 1..].trim_start();
+            }
+            if stack.is_empty() {
+                assert!(root.is_none(), "multiple root elements");
+                root = Some(name.clone());
+            }
+            if !self_closing {
+                stack.push(name);
+            }
+        }
+        assert!(rest.trim().is_empty());
+        assert!(stack.is_empty(), "unclosed elements {:?}", stack);
+        root.unwrap()
+    }
+
+    fn book(path: &str, title: &str, comic: &str, cover: bool) -> Book {
+        Book {
+            id: id(path),
+            path: path.to_string(),
+            title: title.to_string(),
+            comic: comic.to_string(),
+            comic_url: Some(format!("https://example.com/?comic={}&page=1", comic)),
+            index: Some(1),
+            mime: "application/epub+zip",
+            size: 3 << 20,
+            updated: "2020-05-01T00:00:00+00:00".to_string(),
+            cover: if cover {
+                Some((PathBuf::from("1.jpg"), "image/jpeg".to_string()))
+            } else {
+                None
+            },
+        }
+    }
+
+    fn catalog() -> Catalog {
+        Catalog {
+            books: vec![
+                book(
+                    "Tom & Jerry/<第1话>.epub",
+                    "<第1话> \"A&B\"",
+                    "Tom & Jerry",
+                    true,
+                ),
+                book(
+                    "Tom & Jerry/第2话 's.epub",
+                    "第2话 's",
+                    "Tom & Jerry",
+                    false,
+                ),
+                book("其它/1 + 1 = 2?.pdf", "1 + 1 = 2?", UNKNOWN_COMIC, false),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_feeds_well_formed() {
+        let catalog = catalog();
+        let base = "http://127.0.0.1:8080";
+        assert_eq!(check_xml(&catalog.root_feed(base).unwrap()), "feed");
+        assert_eq!(check_xml(&catalog.comics_feed(base).unwrap()), "feed");
+        assert_eq!(check_xml(&catalog.all_feed(base).unwrap()), "feed");
+        for (comic_id, _, _) in catalog.comics() {
+            let feed = catalog.comic_feed(base, &comic_id).unwrap().unwrap();
+            assert_eq!(check_xml(&feed), "feed");
+        }
+        assert!(catalog.comic_feed(base, "missing").unwrap().is_none());
+        let search = catalog.search_feed(base, "tom & <jerry>\"").unwrap();
+        assert_eq!(check_xml(&search), "feed");
+        assert_eq!(
+            check_xml(&opensearch(base).unwrap()),
+            "OpenSearchDescription"
+        );
+    }
+
+    #[test]
+    fn test_feed_escapes_entries() {
+        let feed = catalog().all_feed("http://127.0.0.1:8080").unwrap();
+        assert!(feed.contains("&lt;第1话&gt; &quot;A&amp;B&quot;"));
+        assert!(feed.contains("<name>Tom &amp; Jerry</name>"));
+        // 文件链接中的路径按段编码
+        assert!(feed.contains("%3C%E7%AC%AC1%E8%AF%9D%3E%2Eepub"));
+        assert_eq!(feed.matches("thumbnails").count(), 1);
+        assert_eq!(feed.matches("<entry>").count(), 3);
+    }
+
+    #[test]
+    fn test_search_feed() {
+        let feed = catalog().search_feed("", "JERRY").unwrap();
+        assert_eq!(feed.matches("<entry>").count(), 2);
+        let feed = catalog().search_feed("", "1 + 1").unwrap();
+        assert_eq!(feed.matches("<entry>").count(), 1);
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KB");
+        assert_eq!(human_size(3 << 20), "3.0 MB");
+    }
+}
@@ -0,0 +1,82 @@
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// 是否以 JSON Lines 输出（--json）
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_json(enabled: bool) {
+    JSON.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::SeqCst)
+}
+
+/// 以一行 JSON 输出事件到标准输出，data 的字段与 `event` 字段合并
+pub fn emit(event: &str, data: Value) {
+    println!("{}", event_line(event, data));
+}
+
+/// 事件对应的一行 JSON，不是对象的 data 放在 `data` 字段中
+fn event_line(event: &str, data: Value) -> String {
+    let mut object = match data {
+        Value::Object(object) => object,
+        Value::Null => Map::new(),
+        data => {
+            let mut object = Map::new();
+            object.insert("data".to_string(), data);
+            object
+        }
+    };
+    object.insert("event".to_string(), Value::String(event.to_string()));
+    Value::Object(object).to_string()
+}
+
+/// JSON 模式下输出事件，否则调用 text 输出人类可读的文本
+pub fn report<F: FnOnce()>(event: &str, data: Value, text: F) {
+    if is_json() {
+        emit(event, data);
+    } else {
+        text();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(line: &str) -> Value {
+        assert!(!line.contains('\n'));
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn test_event_line() {
+        let line = event_line("saved", json!({ "title": "第1话\n", "pages": 20 }));
+        assert_eq!(
+            parse(&line),
+            json!({ "event": "saved", "title": "第1话\n", "pages": 20 })
+        );
+        assert_eq!(
+            parse(&event_line("done", Value::Null)),
+            json!({ "event": "done" })
+        );
+        assert_eq!(
+            parse(&event_line("urls", json!(["a", "b"]))),
+            json!({ "event": "urls", "data": ["a", "b"] })
+        );
+        // 事件名覆盖 data 中的同名字段
+        assert_eq!(
+            parse(&event_line("error", json!({ "event": "other" }))),
+            json!({ "event": "error" })
+        );
+    }
+
+    #[test]
+    fn test_report_text() {
+        let mut printed = false;
+        report("saved", json!({}), || printed = true);
+        assert!(printed);
+    }
+}
@@ -0,0 +1,539 @@
+use crate::{save_to, Origin};
+use chrono::offset::Utc;
+use mikack::error::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const QUEUE_FILE: &'static str = "queue.json";
+const LOCK_FILE: &'static str = "queue.json.lock";
+// 等待其它进程释放队列的最长时间
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 任务状态
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Pending,
+    Running,
+    Failed,
+    Done,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Running => "running",
+            JobState::Failed => "failed",
+            JobState::Done => "done",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// 一个章节的下载（及导出）任务
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    /// 章节链接
+    pub url: String,
+    pub title: String,
+    #[serde(default)]
+    pub origin: Origin,
+    /// 下载完成后是否导出
+    pub export: bool,
+    /// 加入队列时的保存格式
+    pub format: Option<String>,
+    /// 合并导出的卷名，同一卷的章节全部完成后一起导出
+    #[serde(default)]
+    pub volume: Option<String>,
+    pub state: JobState,
+    /// 执行中的任务所属的进程，进程已退出的任务可以重新执行
+    #[serde(default)]
+    pub owner: Option<u32>,
+    #[serde(default)]
+    pub attempts: u32,
+    pub error: Option<String>,
+    /// 下载完成后的缓存目录
+    pub base_dir: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// 持久化的任务队列，保存在数据目录的 queue.json 中。
+/// 每次状态变化都会立即写入，进程中断后可以继续执行
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Queue {
+    pub jobs: Vec<Job>,
+}
+
+impl Queue {
+    pub fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join(QUEUE_FILE)
+    }
+
+    /// 读取队列，文件不存在时为空
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = Self::path(data_dir);
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let json = fs::read_to_string(&path)?;
+        serde_json::from_str(&json)
+            .map_err(|e| err_msg(format!("Invalid queue {}: {}", path.display(), e)))
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        save_to(
+            data_dir.to_path_buf(),
+            QUEUE_FILE,
+            &json.as_bytes().to_vec(),
+        )
+    }
+
+    /// 持有锁读取队列，修改后立即保存，其它进程的修改不会被覆盖
+    pub fn update<T, F>(data_dir: &Path, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let _lock = Lock::acquire(data_dir)?;
+        let mut queue = Self::load(data_dir)?;
+        let result = f(&mut queue);
+        queue.save(data_dir)?;
+        Ok(result)
+    }
+
+    /// 加入一个章节，该章节已在等待时更新其导出选项并返回原有任务的编号。
+    /// 执行中的任务不受影响，所属进程已退出时重新等待执行并沿用其编号
+    pub fn push(
+        &mut self,
+        url: &str,
+        title: &str,
+        origin: &Origin,
+        export: bool,
+        format: Option<String>,
+        volume: Option<String>,
+    ) -> u64 {
+        let queued = self.jobs.iter_mut().find(|job| {
+            job.url == url && (job.state == JobState::Pending || job.state == JobState::Running)
+        });
+        if let Some(job) = queued {
+            if job.state == JobState::Running && !job.owner.map(process_alive).unwrap_or(false) {
+                job.state = JobState::Pending;
+                job.owner = None;
+            }
+            if job.state == JobState::Pending {
+                job.export = export;
+                job.format = format;
+                job.volume = volume;
+                if job.title.is_empty() {
+                    job.title = title.to_string();
+                }
+            }
+            job.updated_at = Utc::now().to_rfc3339();
+            return job.id;
+        }
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        let now = Utc::now().to_rfc3339();
+        self.jobs.push(Job {
+            id,
+            url: url.to_string(),
+            title: title.to_string(),
+            origin: origin.clone(),
+            export,
+            format,
+            volume,
+            state: JobState::Pending,
+            owner: None,
+            attempts: 0,
+            error: None,
+            base_dir: None,
+            created_at: now.clone(),
+            updated_at: now,
+        });
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    /// 取出下一个等待的任务（ids 为空时不限）并标记为执行中
+    pub fn start_next(&mut self, ids: Option<&[u64]>) -> Option<Job> {
+        let job = self.jobs.iter_mut().find(|job| {
+            job.state == JobState::Pending && ids.map(|ids| ids.contains(&job.id)).unwrap_or(true)
+        })?;
+        job.state = JobState::Running;
+        job.owner = Some(process::id());
+        job.attempts += 1;
+        job.error = None;
+        job.updated_at = Utc::now().to_rfc3339();
+        Some(job.clone())
+    }
+
+    /// 记录任务的结果，成功时为缓存目录
+    pub fn finish(&mut self, id: u64, result: std::result::Result<String, String>) {
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+            job.owner = None;
+            match result {
+                Ok(base_dir) => {
+                    job.state = JobState::Done;
+                    job.base_dir = Some(base_dir);
+                }
+                Err(e) => {
+                    job.state = JobState::Failed;
+                    job.error = Some(e);
+                }
+            }
+            job.updated_at = Utc::now().to_rfc3339();
+        }
+    }
+
+    /// 所属进程已退出（执行时被中断）的任务重新等待执行，返回数量
+    pub fn recover(&mut self) -> usize {
+        let now = Utc::now().to_rfc3339();
+        let mut n = 0;
+        for job in &mut self.jobs {
+            if job.state == JobState::Running && !job.owner.map(process_alive).unwrap_or(false) {
+                job.state = JobState::Pending;
+                job.owner = None;
+                job.updated_at = now.clone();
+                n += 1;
+            }
+        }
+        n
+    }
+
+    /// 卷中的章节全部完成时返回其缓存目录（按加入队列的顺序），并清除任务的卷名，
+    /// 之后加入的同名卷不会与其合并。仍有未完成的章节时为空
+    pub fn take_volume(&mut self, volume: &str) -> Option<Vec<String>> {
+        let jobs = self
+            .jobs
+            .iter_mut()
+            .filter(|job| job.volume.as_deref() == Some(volume) && job.state != JobState::Cancelled)
+            .collect::<Vec<_>>();
+        if jobs.iter().any(|job| job.state != JobState::Done) {
+            return None;
+        }
+        let mut base_dirs = vec![];
+        for job in jobs {
+            job.volume = None;
+            base_dirs.extend(job.base_dir.clone());
+        }
+        Some(base_dirs)
+    }
+
+    /// 失败的任务及指定的已取消任务重新等待执行，ids 为空时为全部失败的任务。返回数量
+    pub fn retry(&mut self, ids: &[u64]) -> usize {
+        let mut n = self.set_state(ids, JobState::Failed, JobState::Pending);
+        if !ids.is_empty() {
+            n += self.set_state(ids, JobState::Cancelled, JobState::Pending);
+        }
+        n
+    }
+
+    /// 取消等待或失败的任务（ids 为空时为全部），返回数量
+    pub fn cancel(&mut self, ids: &[u64]) -> usize {
+        self.set_state(ids, JobState::Pending, JobState::Cancelled)
+            + self.set_state(ids, JobState::Failed, JobState::Cancelled)
+    }
+
+    /// 移除已完成和已取消的任务，返回数量
+    pub fn clear(&mut self) -> usize {
+        let len = self.jobs.len();
+        self.jobs
+            .retain(|job| job.state != JobState::Done && job.state != JobState::Cancelled);
+        len - self.jobs.len()
+    }
+
+    fn set_state(&mut self, ids: &[u64], from: JobState, to: JobState) -> usize {
+        let now = Utc::now().to_rfc3339();
+        let mut n = 0;
+        for job in &mut self.jobs {
+            if job.state == from && (ids.is_empty() || ids.contains(&job.id)) {
+                job.state = to;
+                job.updated_at = now.clone();
+                n += 1;
+            }
+        }
+        n
+    }
+}
+
+/// queue.json 的锁文件，其中记录了持有锁的进程。
+/// 持有锁的进程异常退出后，锁文件会在下次获取时被清除
+struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    fn acquire(data_dir: &Path) -> Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(LOCK_FILE);
+        let started = Instant::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(process::id().to_string().as_bytes())?;
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    if Self::is_stale(&path) {
+                        let _ = fs::remove_file(&path);
+                        continue;
+                    }
+                    if started.elapsed() > LOCK_TIMEOUT {
+                        return Err(err_msg(format!(
+                            "The queue is locked by another process, remove {} if it is stale",
+                            path.display()
+                        )));
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// 持有锁的进程已退出。刚创建还未写入进程号的锁文件不视为失效
+    fn is_stale(path: &Path) -> bool {
+        match fs::read_to_string(path) {
+            Ok(pid) => match pid.trim().parse() {
+                Ok(pid) => !process_alive(pid),
+                Err(_) => fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .map(|time| time.elapsed().unwrap_or_default() > LOCK_TIMEOUT)
+                    .unwrap_or(false),
+            },
+            // 已被释放
+            Err(_) => false,
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// 进程是否仍在运行，无法判断时视为仍在运行
+fn process_alive(pid: u32) -> bool {
+    if pid == process::id() {
+        return true;
+    }
+    if cfg!(target_os = "linux") {
+        return Path::new("/proc").join(pid.to_string()).exists();
+    }
+    let output = if cfg!(windows) {
+        Command::new("tasklist")
+            .args(&["/FI", &format!("PID eq {}", pid), "/NH"])
+            .stderr(Stdio::null())
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()))
+    } else {
+        Command::new("kill")
+            .args(&["-0", &pid.to_string()])
+            .stderr(Stdio::null())
+            .status()
+            .map(|status| status.success())
+    };
+    output.unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 不可能存在的进程号（超过 Linux 的 pid_max 上限）
+    const EXITED: u32 = 4_194_305;
+
+    fn push(queue: &mut Queue, url: &str) -> u64 {
+        queue.push(url, "", &Origin::default(), true, None, None)
+    }
+
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mikack-cli-queue-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_push_dedupes_pending() {
+        let mut queue = Queue::default();
+        let a = push(&mut queue, "https://example.com/1");
+        let b = push(&mut queue, "https://example.com/2");
+        assert_eq!((a, b), (1, 2));
+        assert_eq!(push(&mut queue, "https://example.com/1"), a);
+        assert_eq!(queue.jobs.len(), 2);
+    }
+
+    #[test]
+    fn test_push_keeps_running_job() {
+        let mut queue = Queue::default();
+        let id = push(&mut queue, "https://example.com/1");
+        assert_eq!(queue.start_next(None).unwrap().owner, Some(process::id()));
+        // 仍在执行中的任务不会被重置
+        assert_eq!(push(&mut queue, "https://example.com/1"), id);
+        assert_eq!(queue.get(id).unwrap().state, JobState::Running);
+        assert_eq!(queue.recover(), 0);
+        assert!(queue.start_next(Some(&[id])).is_none());
+    }
+
+    #[test]
+    fn test_push_requeues_interrupted_job() {
+        let mut queue = Queue::default();
+        let id = push(&mut queue, "https://example.com/1");
+        queue.start_next(None);
+        // 模拟执行中被中断：所属进程已退出
+        queue.jobs[0].owner = Some(EXITED);
+        assert_eq!(push(&mut queue, "https://example.com/1"), id);
+        assert_eq!(queue.get(id).unwrap().state, JobState::Pending);
+        assert_eq!(queue.get(id).unwrap().owner, None);
+        assert_eq!(queue.start_next(Some(&[id])).unwrap().id, id);
+        assert_eq!(queue.get(id).unwrap().attempts, 2);
+    }
+
+    #[test]
+    fn test_push_updates_pending_job() {
+        let mut queue = Queue::default();
+        let id = push(&mut queue, "https://example.com/1");
+        let origin = Origin::default();
+        let format = Some("epub".to_string());
+        let volume = Some("第一卷".to_string());
+        let url = "https://example.com/1";
+        assert_eq!(
+            queue.push(url, "第1话", &origin, false, format.clone(), volume.clone()),
+            id
+        );
+        let job = queue.get(id).unwrap();
+        assert_eq!(
+            (job.export, &job.format, &job.volume, job.title.as_str()),
+            (false, &format, &volume, "第1话")
+        );
+        // 执行中的任务不更新
+        queue.start_next(None);
+        queue.push(url, "", &origin, true, None, None);
+        assert_eq!(queue.get(id).unwrap().format, format);
+    }
+
+    #[test]
+    fn test_push_after_done_creates_new_job() {
+        let mut queue = Queue::default();
+        let id = push(&mut queue, "https://example.com/1");
+        queue.start_next(None);
+        queue.finish(id, Ok("cache".to_string()));
+        assert_eq!(push(&mut queue, "https://example.com/1"), id + 1);
+    }
+
+    #[test]
+    fn test_start_next_with_ids() {
+        let mut queue = Queue::default();
+        let a = push(&mut queue, "https://example.com/1");
+        let b = push(&mut queue, "https://example.com/2");
+        assert_eq!(queue.start_next(Some(&[b])).unwrap().id, b);
+        assert!(queue.start_next(Some(&[b])).is_none());
+        assert_eq!(queue.start_next(None).unwrap().id, a);
+        assert!(queue.start_next(None).is_none());
+    }
+
+    #[test]
+    fn test_finish_retry_cancel_clear() {
+        let mut queue = Queue::default();
+        let a = push(&mut queue, "https://example.com/1");
+        let b = push(&mut queue, "https://example.com/2");
+        let c = push(&mut queue, "https://example.com/3");
+        queue.start_next(None);
+        queue.finish(a, Err("HTTP 404".to_string()));
+        assert_eq!(queue.get(a).unwrap().state, JobState::Failed);
+        assert_eq!(queue.get(a).unwrap().error.as_deref(), Some("HTTP 404"));
+
+        assert_eq!(queue.cancel(&[b]), 1);
+        // 未指定编号时不重试已取消的任务
+        assert_eq!(queue.retry(&[]), 1);
+        assert_eq!(queue.get(b).unwrap().state, JobState::Cancelled);
+        assert_eq!(queue.retry(&[b]), 1);
+        assert_eq!(queue.get(b).unwrap().state, JobState::Pending);
+
+        queue.start_next(Some(&[c]));
+        assert_eq!(queue.recover(), 0);
+        queue.jobs[2].owner = Some(EXITED);
+        assert_eq!(queue.recover(), 1);
+        assert_eq!(queue.get(c).unwrap().state, JobState::Pending);
+        assert_eq!(queue.cancel(&[]), 3);
+        assert_eq!(queue.clear(), 3);
+        assert!(queue.jobs.is_empty());
+    }
+
+    #[test]
+    fn test_take_volume() {
+        let mut queue = Queue::default();
+        let volume = Some("第一卷".to_string());
+        let origin = Origin::default();
+        let ids = (1..=3)
+            .map(|i| {
+                let url = format!("https://example.com/{}", i);
+                queue.push(&url, "", &origin, true, None, volume.clone())
+            })
+            .collect::<Vec<_>>();
+        push(&mut queue, "https://example.com/4");
+        for id in &ids[..2] {
+            queue.start_next(Some(&[*id]));
+            queue.finish(*id, Ok(format!("cache/{}", id)));
+        }
+        assert_eq!(queue.take_volume("第一卷"), None);
+        // 取消的章节不再等待
+        queue.cancel(&[ids[2]]);
+        assert_eq!(
+            queue.take_volume("第一卷"),
+            Some(vec!["cache/1".to_string(), "cache/2".to_string()])
+        );
+        assert_eq!(queue.take_volume("第一卷"), Some(vec![]));
+        assert!(queue.jobs[..2].iter().all(|job| job.volume.is_none()));
+    }
+
+    #[test]
+    fn test_update_is_locked() {
+        let dir = data_dir("locked");
+        let workers = (0..8)
+            .map(|i| {
+                let dir = dir.clone();
+                thread::spawn(move || {
+                    for j in 0..10 {
+                        let url = format!("https://example.com/{}/{}", i, j);
+                        Queue::update(&dir, |queue| push(queue, &url)).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let queue = Queue::load(&dir).unwrap();
+        let mut ids = queue.jobs.iter().map(|job| job.id).collect::<Vec<_>>();
+        ids.dedup();
+        assert_eq!(ids, (1..=80).collect::<Vec<_>>());
+        assert!(!dir.join(LOCK_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_lock() {
+        let dir = data_dir("stale");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(LOCK_FILE), EXITED.to_string()).unwrap();
+        assert_eq!(
+            Queue::update(&dir, |queue| push(queue, "https://example.com/1")).unwrap(),
+            1
+        );
+        assert!(!dir.join(LOCK_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+use chrono::{offset::Utc, DateTime};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 收到 429 等限流响应后的最小及最大请求间隔
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// Retry-After 的上限，避免异常的响应头导致长时间等待
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// 限速设置，对应配置文件中的 `rate-limit` 和 `image-rate-limit`
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct RateLimit {
+    /// 每秒最多的请求数
+    pub requests_per_second: Option<f64>,
+    /// 两次请求之间的最小间隔（毫秒）
+    pub min_delay: Option<u64>,
+}
+
+impl RateLimit {
+    pub fn per_second(requests_per_second: f64) -> Self {
+        Self {
+            requests_per_second: Some(requests_per_second),
+            min_delay: None,
+        }
+    }
+
+    /// 两次请求之间的间隔，取两项设置中较长的一个
+    pub fn interval(&self) -> Duration {
+        let rate = match self.requests_per_second {
+            Some(rps) if rps > 0.0 => Duration::from_secs_f64(1.0 / rps),
+            _ => Duration::from_secs(0),
+        };
+        rate.max(Duration::from_millis(self.min_delay.unwrap_or(0)))
+    }
+}
+
+struct HostState {
+    /// 下一个请求最早可以发出的时间
+    next: Instant,
+    /// 被限流后额外增加的间隔，请求成功后逐渐恢复
+    backoff: Duration,
+}
+
+/// 按主机名限速，所有下载线程共用同一个实例
+#[derive(Default)]
+pub struct RateLimiter {
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+impl RateLimiter {
+    /// 等待直到可以向 host 发出下一个请求，并为其预留时间
+    pub fn wait(&self, host: &str, limit: Option<&RateLimit>) {
+        let delay = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let now = Instant::now();
+            let state = hosts.entry(host.to_string()).or_insert(HostState {
+                next: now,
+                backoff: Duration::from_secs(0),
+            });
+            let at = state.next.max(now);
+            let interval = limit
+                .map(|limit| limit.interval())
+                .unwrap_or_default()
+                .max(state.backoff);
+            state.next = at + interval;
+            at - now
+        };
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+    }
+
+    /// 被限流（429 或带 Retry-After 的响应）后放慢对 host 的请求
+    pub fn slow_down(&self, host: &str, retry_after: Option<Duration>) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let now = Instant::now();
+        let state = hosts.entry(host.to_string()).or_insert(HostState {
+            next: now,
+            backoff: Duration::from_secs(0),
+        });
+        state.backoff = (state.backoff * 2).max(MIN_BACKOFF).min(MAX_BACKOFF);
+        let wait = retry_after
+            .map(|retry_after| retry_after.min(MAX_RETRY_AFTER))
+            .unwrap_or(state.backoff);
+        state.next = state.next.max(now + wait);
+    }
+
+    /// 请求成功后逐渐恢复被放慢的速度
+    pub fn speed_up(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(state) = hosts.get_mut(host) {
+            state.backoff /= 2;
+            if state.backoff < MIN_BACKOFF {
+                state.backoff = Duration::from_secs(0);
+            }
+        }
+    }
+}
+
+/// 解析 Retry-After 响应头（秒数或 HTTP 日期）
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(limiter: &RateLimiter, host: &str) -> Duration {
+        limiter.hosts.lock().unwrap()[host].backoff
+    }
+
+    #[test]
+    fn test_interval() {
+        assert_eq!(RateLimit::default().interval(), Duration::from_secs(0));
+        assert_eq!(
+            RateLimit::per_second(4.0).interval(),
+            Duration::from_millis(250)
+        );
+        // 同时设置时取较长的间隔
+        let limit = RateLimit {
+            requests_per_second: Some(4.0),
+            min_delay: Some(500),
+        };
+        assert_eq!(limit.interval(), Duration::from_millis(500));
+        assert_eq!(
+            RateLimit::per_second(0.0).interval(),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn test_wait() {
+        let limiter = RateLimiter::default();
+        let limit = RateLimit {
+            requests_per_second: None,
+            min_delay: Some(50),
+        };
+        let start = Instant::now();
+        limiter.wait("a.com", Some(&limit));
+        limiter.wait("b.com", Some(&limit));
+        assert!(start.elapsed() < Duration::from_millis(50));
+        limiter.wait("a.com", Some(&limit));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_slow_down_and_speed_up() {
+        let limiter = RateLimiter::default();
+        limiter.slow_down("a.com", None);
+        assert_eq!(backoff(&limiter, "a.com"), MIN_BACKOFF);
+        limiter.slow_down("a.com", None);
+        assert_eq!(backoff(&limiter, "a.com"), MIN_BACKOFF * 2);
+        for _ in 0..10 {
+            limiter.slow_down("a.com", Some(Duration::from_secs(3600)));
+        }
+        assert_eq!(backoff(&limiter, "a.com"), MAX_BACKOFF);
+        // Retry-After 超出上限时按上限等待
+        let next = limiter.hosts.lock().unwrap()["a.com"].next;
+        assert!(next <= Instant::now() + MAX_RETRY_AFTER);
+
+        limiter.speed_up("a.com");
+        assert_eq!(backoff(&limiter, "a.com"), MAX_BACKOFF / 2);
+        for _ in 0..10 {
+            limiter.speed_up("a.com");
+        }
+        assert_eq!(backoff(&limiter, "a.com"), Duration::from_secs(0));
+        // 未被限流的主机不受影响
+        limiter.speed_up("b.com");
+        assert!(!limiter.hosts.lock().unwrap().contains_key("b.com"));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::from_secs(0)));
+        let future = (Utc::now() + chrono::Duration::seconds(90))
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        let wait = parse_retry_after(&future).unwrap();
+        assert!(wait > Duration::from_secs(80) && wait <= Duration::from_secs(90));
+        // 已经过去的时间及无法解析的值
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("-1"), None);
+    }
+}
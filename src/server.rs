@@ -0,0 +1,694 @@
+use crate::downloader::{Progress, Session};
+use crate::opds::{self, Catalog};
+use crate::queue::{self, Queue};
+use crate::settings::Settings;
+use crate::tasks::{self, Target};
+use crate::{platform, select_chapters, Origin};
+use chrono::offset::Utc;
+use mikack::error::*;
+use mikack::extractors;
+use mikack::models::{Comic, Page};
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+use uuid::Uuid;
+
+pub const DEFAULT_LISTEN: &'static str = "127.0.0.1:8080";
+
+/// 任务状态
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+/// 下载任务：将链接对应的章节加入持久化的队列，依次下载并逐章导出
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub url: String,
+    /// 漫画主页的章节选择规则
+    pub chapters: Option<String>,
+    pub format: String,
+    pub state: JobState,
+    pub created_at: String,
+    /// 选中的章节数量及已完成的数量
+    pub total: usize,
+    pub finished: usize,
+    /// 正在下载的章节标题及其页面进度
+    pub current: Option<String>,
+    pub pages: usize,
+    pub downloaded: usize,
+    /// 导出文件相对于输出目录的路径，可通过 `/files/<path>` 下载
+    pub outputs: Vec<String>,
+    /// 章节在队列中的任务编号，服务中断后可以通过 `queue run` 继续
+    pub queue_ids: Vec<u64>,
+    /// 下载或导出失败的章节及原因，不影响其它章节
+    pub failures: Vec<String>,
+    pub error: Option<String>,
+}
+
+/// 创建任务的请求体
+#[derive(Debug, Deserialize)]
+struct NewJob {
+    url: String,
+    chapters: Option<String>,
+    format: Option<String>,
+}
+
+struct State {
+    session: Session,
+    settings: Settings,
+    /// 允许从浏览器跨域调用的来源，未设置时不发送 CORS 响应头
+    allow_origin: Option<String>,
+    jobs: Mutex<Vec<Job>>,
+    queue: Mutex<mpsc::Sender<String>>,
+}
+
+impl State {
+    fn job(&self, id: &str) -> Option<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    fn update<F: FnOnce(&mut Job)>(&self, id: &str, f: F) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.iter_mut().find(|job| job.id == id) {
+            f(job);
+        }
+    }
+}
+
+enum Reply {
+    Json(u16, Value),
+    File(PathBuf),
+    Data(String, Vec<u8>),
+    /// 跨域请求的预检
+    Preflight,
+}
+
+fn error(status: u16, message: &str) -> Reply {
+    Reply::Json(status, json!({ "error": message }))
+}
+
+/// 在 addr 上启动 HTTP API 服务。请求在各自的线程中处理，下载任务按提交顺序依次执行。
+/// 只有 allow_origin 指定的网页可以跨域调用
+pub fn serve(
+    addr: &str,
+    allow_origin: Option<&str>,
+    session: Session,
+    settings: Settings,
+) -> Result<()> {
+    let server =
+        Server::http(addr).map_err(|e| err_msg(format!("Failed to listen on {}: {}", addr, e)))?;
+    run(server, allow_origin, session, settings);
+    Ok(())
+}
+
+fn run(server: Server, allow_origin: Option<&str>, session: Session, settings: Settings) {
+    let (queue, jobs) = mpsc::channel::<String>();
+    let state = Arc::new(State {
+        session,
+        settings,
+        allow_origin: allow_origin.map(|origin| origin.to_string()),
+        jobs: Mutex::new(vec![]),
+        queue: Mutex::new(queue),
+    });
+    {
+        let state = state.clone();
+        thread::spawn(move || {
+            for id in jobs {
+                run_job(&state, &id);
+            }
+        });
+    }
+    for request in server.incoming_requests() {
+        let state = state.clone();
+        thread::spawn(move || handle(&state, request));
+    }
+}
+
+fn handle(state: &State, mut request: Request) {
+    // 只对允许的来源发送 CORS 响应头
+    let origin = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Origin"))
+        .map(|h| h.value.as_str().to_string())
+        .filter(|origin| state.allow_origin.as_deref() == Some(origin.as_str()));
+    let origin = origin.as_deref();
+    let reply = match route(state, &mut request) {
+        Ok(reply) => reply,
+        Err(e) => error(500, &e.to_string()),
+    };
+    // 客户端断开时忽略写入错误
+    let _ = match reply {
+        Reply::Json(status, value) => request.respond(cors(
+            Response::from_string(value.to_string())
+                .with_status_code(status)
+                .with_header(header("Content-Type", "application/json; charset=utf-8")),
+            origin,
+        )),
+        Reply::File(path) => match File::open(&path) {
+            Ok(file) => request.respond(cors(
+                Response::from_file(file).with_header(header("Content-Type", content_type(&path))),
+                origin,
+            )),
+            Err(_) => request.respond(Response::from_string("Not found").with_status_code(404)),
+        },
+        Reply::Data(mime, bytes) => request.respond(cors(
+            Response::from_data(bytes).with_header(header("Content-Type", &mime)),
+            origin,
+        )),
+        Reply::Preflight => request.respond(match origin {
+            Some(_) => cors(Response::empty(204), origin)
+                .with_header(header("Access-Control-Allow-Methods", "GET, POST, OPTIONS"))
+                .with_header(header("Access-Control-Allow-Headers", "Content-Type")),
+            None => Response::empty(204),
+        }),
+    };
+}
+
+/// 允许 origin 跨域读取响应
+fn cors<R: Read>(response: Response<R>, origin: Option<&str>) -> Response<R> {
+    match origin {
+        Some(origin) => response
+            .with_header(header("Access-Control-Allow-Origin", origin))
+            .with_header(header("Vary", "Origin")),
+        None => response,
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn route(state: &State, request: &mut Request) -> Result<Reply> {
+    let url = Url::parse(&format!("http://localhost{}", request.url()))?;
+    let query = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.to_string())
+    };
+    let segments = url
+        .path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    match (request.method().clone(), segments.as_slice()) {
+        (Method::Options, _) => Ok(Reply::Preflight),
+        (Method::Get, ["api", "platforms"]) => Ok(Reply::Json(200, platforms())),
+        (Method::Get, ["api", "platforms", domain, "index"]) => {
+            let page = match query("page") {
+                Some(page) => page.parse()?,
+                None => 1,
+            };
+            let extractor = tasks::get_exrt(domain.to_string())?;
+            tasks::throttle(&state.session, &state.settings, domain);
+            Ok(Reply::Json(200, comics(&extractor.index(page)?)))
+        }
+        (Method::Get, ["api", "chapters"]) => match query("url") {
+            Some(url) => match tasks::resolve(&url)? {
+                (extractor, Target::Comic(mut comic)) => {
+                    tasks::throttle(&state.session, &state.settings, &platform(&url));
+                    extractor.fetch_chapters(&mut comic)?;
+                    Ok(Reply::Json(200, chapters(&comic)))
+                }
+                _ => Ok(error(400, "Not a comic home page")),
+            },
+            None => Ok(error(400, "Missing url parameter")),
+        },
+        (Method::Get, ["api", "jobs"]) => {
+            let jobs = state.jobs.lock().unwrap().clone();
+            Ok(Reply::Json(200, serde_json::to_value(jobs)?))
+        }
+        (Method::Post, ["api", "jobs"]) => {
+            // 要求 JSON 请求体，网页无法不经预检直接提交任务
+            let is_json = request.headers().iter().any(|h| {
+                h.field.equiv("Content-Type")
+                    && h.value
+                        .as_str()
+                        .to_ascii_lowercase()
+                        .starts_with("application/json")
+            });
+            if !is_json {
+                return Ok(error(415, "Content-Type must be application/json"));
+            }
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body)?;
+            match serde_json::from_str::<NewJob>(&body) {
+                Ok(new_job) => create_job(state, new_job),
+                Err(e) => Ok(error(400, &format!("Invalid job: {}", e))),
+            }
+        }
+        (Method::Get, ["api", "jobs", id]) => match state.job(id) {
+            Some(job) => Ok(Reply::Json(200, serde_json::to_value(job)?)),
+            None => Ok(error(404, "Job not found")),
+        },
+        (Method::Get, ["api", "files"]) => {
+            let mut files = vec![];
+            list_files(
+                &state.settings.output_dir,
+                &state.settings.output_dir,
+                &mut files,
+            );
+            Ok(Reply::Json(200, json!(files)))
+        }
+        (Method::Get, ["opds", path @ ..]) => {
+            let base = match request.headers().iter().find(|h| h.field.equiv("Host")) {
+                Some(host) => format!("http://{}", host.value),
+                None => String::new(),
+            };
+            route_opds(state, &base, path, query("q"))
+        }
+        (Method::Get, ["files", path @ ..]) => {
+            match output_file(&state.settings.output_dir, path) {
+                Some(path) => Ok(Reply::File(path)),
+                None => Ok(error(404, "File not found")),
+            }
+        }
+        _ => Ok(error(404, "Not found")),
+    }
+}
+
+/// OPDS 目录，base 为 Feed 中链接的前缀
+fn route_opds(state: &State, base: &str, path: &[&str], q: Option<String>) -> Result<Reply> {
+    if let ["search.xml"] = path {
+        let xml = opds::opensearch(base)?;
+        return Ok(Reply::Data(
+            "application/opensearchdescription+xml".to_string(),
+            xml.into_bytes(),
+        ));
+    }
+    let catalog = Catalog::scan(&state.settings)?;
+    let (mime, xml) = match path {
+        [] => (opds::NAVIGATION, catalog.root_feed(base)?),
+        ["all"] => (opds::ACQUISITION, catalog.all_feed(base)?),
+        ["comics"] => (opds::NAVIGATION, catalog.comics_feed(base)?),
+        ["comics", id] => match catalog.comic_feed(base, id)? {
+            Some(xml) => (opds::ACQUISITION, xml),
+            None => return Ok(error(404, "Comic not found")),
+        },
+        ["search"] => (
+            opds::ACQUISITION,
+            catalog.search_feed(base, &q.unwrap_or_default())?,
+        ),
+        ["covers", id] => {
+            return Ok(match catalog.book(id).and_then(|book| book.cover.clone()) {
+                Some((path, mime)) => Reply::Data(mime, fs::read(path)?),
+                None => error(404, "Cover not found"),
+            })
+        }
+        ["thumbnails", id] => {
+            let thumbnail = match catalog.book(id) {
+                Some(book) => opds::thumbnail(book)?,
+                None => None,
+            };
+            return Ok(match thumbnail {
+                Some(bytes) => Reply::Data("image/jpeg".to_string(), bytes),
+                None => error(404, "Cover not found"),
+            });
+        }
+        _ => return Ok(error(404, "Not found")),
+    };
+    Ok(Reply::Data(
+        format!("{};charset=utf-8", mime),
+        xml.into_bytes(),
+    ))
+}
+
+fn platforms() -> Value {
+    extractors::PLATFORMS
+        .iter()
+        .map(|(domain, name)| json!({ "domain": domain, "name": name }))
+        .collect()
+}
+
+fn comics(comics: &[Comic]) -> Value {
+    comics
+        .iter()
+        .map(|comic| json!({ "title": comic.title, "url": comic.url }))
+        .collect()
+}
+
+fn chapters(comic: &Comic) -> Value {
+    let chapters = comic
+        .chapters
+        .iter()
+        .enumerate()
+        .map(|(i, chapter)| json!({ "index": i + 1, "title": chapter.title, "url": chapter.url }))
+        .collect::<Vec<_>>();
+    json!({ "title": comic.title, "url": comic.url, "chapters": chapters })
+}
+
+fn create_job(state: &State, new_job: NewJob) -> Result<Reply> {
+    let target = match extractors::domain_route(&new_job.url) {
+        Some(target) => target,
+        None => return Ok(error(400, "This link is not supported")),
+    };
+    if let (extractors::DomainRoute::Comic(_), None) = (target, &new_job.chapters) {
+        return Ok(error(400, "Missing chapter rule for a comic home page"));
+    }
+    let format = new_job
+        .format
+        .or(state.settings.format.clone())
+        .unwrap_or("none".to_string());
+    let job = Job {
+        id: Uuid::new_v4().to_simple().to_string(),
+        url: new_job.url,
+        chapters: new_job.chapters,
+        format,
+        state: JobState::Queued,
+        created_at: Utc::now().to_rfc3339(),
+        total: 0,
+        finished: 0,
+        current: None,
+        pages: 0,
+        downloaded: 0,
+        outputs: vec![],
+        queue_ids: vec![],
+        failures: vec![],
+        error: None,
+    };
+    state.jobs.lock().unwrap().push(job.clone());
+    state
+        .queue
+        .lock()
+        .unwrap()
+        .send(job.id.clone())
+        .map_err(|_| err_msg("Job worker exited unexpectedly"))?;
+    Ok(Reply::Json(201, serde_json::to_value(job)?))
+}
+
+fn run_job(state: &State, id: &str) {
+    let job = match state.job(id) {
+        Some(job) => job,
+        None => return,
+    };
+    state.update(id, |job| job.state = JobState::Running);
+    let result = execute(state, &job);
+    state.update(id, |job| {
+        job.current = None;
+        match result {
+            Ok(()) => job.state = JobState::Done,
+            Err(e) => {
+                job.state = JobState::Failed;
+                job.error = Some(e.to_string());
+            }
+        }
+    });
+}
+
+fn execute(state: &State, job: &Job) -> Result<()> {
+    // 每个章节单独导出，不合并为卷
+    let settings = Settings {
+        format: Some(job.format.clone()),
+        volume: None,
+        ..state.settings.clone()
+    };
+    let ids = match tasks::resolve(&job.url)? {
+        (_, Target::Chapter(chapter)) => Queue::update(&settings.data_dir, |queue| {
+            vec![queue.push(
+                &chapter.url,
+                "",
+                &Origin::default(),
+                true,
+                settings.format.clone(),
+                None,
+            )]
+        })?,
+        (extractor, Target::Comic(mut comic)) => {
+            tasks::fetch_chapters(&state.session, &settings, extractor, &mut comic)?;
+            let rule = job
+                .chapters
+                .as_deref()
+                .ok_or_else(|| err_msg("Missing chapter rule"))?;
+            let selects = select_chapters(rule, comic.chapters.len())?;
+            tasks::enqueue_chapters(&settings, &comic, &selects, true)?
+        }
+    };
+    state.update(&job.id, |job| {
+        job.total = ids.len();
+        job.queue_ids = ids.clone();
+    });
+    let start = |_: &queue::Job| JobProgress { state, id: &job.id };
+    let finish = |queued: &queue::Job, result: &Result<tasks::Done>| {
+        state.update(&job.id, |job| match result {
+            Ok(done) => {
+                job.finished += 1;
+                for (_, path) in &done.outputs {
+                    let output = path
+                        .strip_prefix(&settings.output_dir)
+                        .unwrap_or(path)
+                        .to_string_lossy()
+                        .to_string();
+                    job.outputs.push(output);
+                }
+            }
+            Err(e) => {
+                let name = if queued.title.is_empty() {
+                    &queued.url
+                } else {
+                    &queued.title
+                };
+                job.failures.push(format!("{}: {}", name, e));
+            }
+        })
+    };
+    let failed = tasks::run_queue(&state.session, &settings, Some(&ids), start, finish)?;
+    if failed > 0 {
+        return Err(err_msg(format!(
+            "{} of {} chapters failed",
+            failed,
+            ids.len()
+        )));
+    }
+    Ok(())
+}
+
+/// 将页面进度写入任务
+struct JobProgress<'a> {
+    state: &'a State,
+    id: &'a str,
+}
+
+impl<'a> Progress for JobProgress<'a> {
+    fn start(&mut self, title: &str, _base_dir: &str, total: usize) {
+        self.state.update(self.id, |job| {
+            job.current = Some(title.to_string());
+            job.pages = total;
+            job.downloaded = 0;
+        });
+    }
+
+    fn page(&mut self, _index: usize, _page: &Page, _result: &Result<bool>) {
+        self.state.update(self.id, |job| job.downloaded += 1);
+    }
+}
+
+/// 输出目录中的文件（相对路径及字节数）
+fn list_files(root: &Path, dir: &Path, files: &mut Vec<Value>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            list_files(root, &path, files);
+        } else if let (Ok(relative), Ok(metadata)) = (path.strip_prefix(root), entry.metadata()) {
+            files.push(json!({ "path": relative.to_string_lossy(), "size": metadata.len() }));
+        }
+    }
+}
+
+/// 请求路径对应的输出文件，拒绝 `..`、绝对路径等跳出输出目录的路径
+fn output_file(output_dir: &Path, segments: &[&str]) -> Option<PathBuf> {
+    let mut path = output_dir.to_path_buf();
+    for segment in segments {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        if segment.contains('/') || segment.contains('\\') {
+            return None;
+        }
+        // 每一段只能是普通的文件名（排除 `.`、`..` 及盘符等）
+        let mut components = Path::new(segment.as_ref()).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => path.push(segment.as_ref()),
+            _ => return None,
+        }
+    }
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("epub") => "application/epub+zip",
+        Some("cbz") => "application/vnd.comicbook+zip",
+        Some("pdf") => "application/pdf",
+        Some("mobi") => "application/x-mobipocket-ebook",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        Some("gif") => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::ClientOptions;
+    use reqwest::blocking::Client;
+    use reqwest::header::{ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_TYPE, ORIGIN};
+
+    const ALLOWED: &'static str = "http://localhost:3000";
+
+    /// 测试用的输出目录，包含 `a/b.epub` 及目录之外的 `secret`
+    fn output_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("mikack-cli-server-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("output/a")).unwrap();
+        fs::write(dir.join("output/a/b.epub"), b"epub").unwrap();
+        fs::write(dir.join("secret"), b"secret").unwrap();
+        dir
+    }
+
+    /// 在随机端口上启动服务，返回其地址
+    fn start(name: &str) -> String {
+        let dir = output_dir(name);
+        let settings = Settings {
+            output_dir: dir.join("output"),
+            data_dir: dir.join("data"),
+            cache_dir: dir.join("cache"),
+            ..Settings::default()
+        };
+        let session = Session::new(&ClientOptions::default()).unwrap();
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}", server.server_addr());
+        thread::spawn(move || run(server, Some(ALLOWED), session, settings));
+        addr
+    }
+
+    #[test]
+    fn test_output_file() {
+        let dir = output_dir("output-file");
+        let output = dir.join("output");
+        assert_eq!(
+            output_file(&output, &["a", "b.epub"]),
+            Some(output.join("a/b.epub"))
+        );
+        assert_eq!(output_file(&output, &["a"]), None);
+        assert_eq!(output_file(&output, &["..", "secret"]), None);
+        assert_eq!(output_file(&output, &["a", "..", "..", "secret"]), None);
+        assert_eq!(output_file(&output, &["%2E%2E", "secret"]), None);
+        assert_eq!(output_file(&output, &["."]), None);
+        // 编码的分隔符
+        assert_eq!(output_file(&output, &["a%2Fb.epub"]), None);
+        assert_eq!(output_file(&output, &["..%2Fsecret"]), None);
+        assert_eq!(output_file(&output, &["..%5Csecret"]), None);
+        // 绝对路径
+        let absolute = dir.join("secret").display().to_string();
+        let encoded = absolute.replace('/', "%2F");
+        assert_eq!(output_file(&output, &[encoded.as_str()]), None);
+        assert_eq!(output_file(&output, &["C:%5Csecret"]), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_route() {
+        let addr = start("files");
+        let client = Client::new();
+        let get = |path: &str| client.get(&format!("{}{}", addr, path)).send().unwrap();
+        let resp = get("/files/a/b.epub");
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.text().unwrap(), "epub");
+        for path in &[
+            "/files/../secret",
+            "/files/a/../../secret",
+            "/files/%2E%2E/secret",
+            "/files/..%2Fsecret",
+        ] {
+            assert_eq!(get(path).status(), 404, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_cors() {
+        let addr = start("cors");
+        let client = Client::new();
+        let resp = client
+            .get(&format!("{}/api/jobs", addr))
+            .header(ORIGIN, ALLOWED)
+            .send()
+            .unwrap();
+        assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], ALLOWED);
+        // 不允许的来源不会得到 CORS 响应头，预检同样如此
+        let resp = client
+            .get(&format!("{}/api/jobs", addr))
+            .header(ORIGIN, "http://evil.example")
+            .send()
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        let resp = client
+            .request(reqwest::Method::OPTIONS, &format!("{}/api/jobs", addr))
+            .header(ORIGIN, "http://evil.example")
+            .send()
+            .unwrap();
+        assert_eq!(resp.status(), 204);
+        assert!(resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert!(resp.headers().get("Access-Control-Allow-Methods").is_none());
+    }
+
+    #[test]
+    fn test_create_job_requires_json() {
+        let addr = start("create-job");
+        let client = Client::new();
+        let body = r#"{"url": "https://www.dm5.com/m1029843/"}"#;
+        let resp = client
+            .post(&format!("{}/api/jobs", addr))
+            .header(CONTENT_TYPE, "text/plain")
+            .header(ORIGIN, "http://evil.example")
+            .body(body)
+            .send()
+            .unwrap();
+        assert_eq!(resp.status(), 415);
+        let resp = client
+            .post(&format!("{}/api/jobs", addr))
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body("url=https%3A%2F%2Fwww.dm5.com%2Fm1029843%2F")
+            .send()
+            .unwrap();
+        assert_eq!(resp.status(), 415);
+        let resp = client
+            .post(&format!("{}/api/jobs", addr))
+            .header(CONTENT_TYPE, "application/json")
+            .body("url=1")
+            .send()
+            .unwrap();
+        assert_eq!(resp.status(), 400);
+        // 没有创建任何任务
+        let jobs = client
+            .get(&format!("{}/api/jobs", addr))
+            .send()
+            .unwrap()
+            .text()
+            .unwrap();
+        let jobs: Value = serde_json::from_str(&jobs).unwrap();
+        assert_eq!(jobs, json!([]));
+    }
+}
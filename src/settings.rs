@@ -0,0 +1,292 @@
+use crate::downloader::{ClientOptions, DownloadOptions, RetryPolicy};
+use crate::ratelimit::RateLimit;
+use crate::{exporters, CACHE_DIR, DATA_DIR, OUTPUT_DIR};
+use mikack::error::*;
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_JOBS: usize = 4;
+pub const DEFAULT_TIMEOUT: u64 = 30;
+
+/// 平台专属设置，对应配置文件中的 `[platforms."<domain>"]`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PlatformSettings {
+    /// 下载该平台的页面时附加的请求头
+    pub headers: HashMap<String, String>,
+    /// 覆盖全局的输出文件名模板
+    pub filename: Option<String>,
+    /// 覆盖全局的页面（漫画列表、章节列表等）请求限速
+    pub rate_limit: Option<RateLimit>,
+    /// 覆盖全局的图片请求限速
+    pub image_rate_limit: Option<RateLimit>,
+}
+
+/// 运行设置，依次由默认值、配置文件和命令行参数填充
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Settings {
+    /// 保存格式，多个格式以逗号分隔
+    pub format: Option<String>,
+    /// 导出文件的保存目录
+    pub output_dir: PathBuf,
+    /// 下载的图片及元数据的缓存目录
+    pub cache_dir: PathBuf,
+    /// 订阅列表等数据的保存目录
+    pub data_dir: PathBuf,
+    /// 并发下载的页面数量
+    pub jobs: usize,
+    pub max_attempts: Option<u32>,
+    /// 读取响应的空闲超时（秒）
+    pub timeout: u64,
+    /// 连接超时（秒），未指定时与读取超时相同
+    pub connect_timeout: Option<u64>,
+    pub user_agent: Option<String>,
+    pub proxy: Option<String>,
+    pub insecure: bool,
+    pub rtl: bool,
+    /// 跳过下载历史中已有的章节（默认提示后重新下载）
+    pub skip_downloaded: bool,
+    /// 输出文件名模板
+    pub filename: Option<String>,
+    /// 每个平台的页面请求限速
+    pub rate_limit: Option<RateLimit>,
+    /// 每个图片主机的请求限速
+    pub image_rate_limit: Option<RateLimit>,
+    pub platforms: HashMap<String, PlatformSettings>,
+    /// 章节选择规则，仅来自命令行
+    #[serde(skip)]
+    pub chapters: Option<String>,
+    /// 合卷的卷名，仅来自命令行
+    #[serde(skip)]
+    pub volume: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            format: None,
+            output_dir: default_output_dir(),
+            cache_dir: default_cache_dir(),
+            data_dir: default_data_dir(),
+            jobs: DEFAULT_JOBS,
+            max_attempts: None,
+            timeout: DEFAULT_TIMEOUT,
+            connect_timeout: None,
+            user_agent: None,
+            proxy: None,
+            insecure: false,
+            rtl: false,
+            skip_downloaded: false,
+            filename: None,
+            rate_limit: None,
+            image_rate_limit: None,
+            platforms: HashMap::new(),
+            chapters: None,
+            volume: None,
+        }
+    }
+}
+
+impl Settings {
+    /// 默认的配置文件路径（eg: ~/.config/mikack-cli/config.toml）
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("mikack-cli").join("config.toml"))
+    }
+
+    /// 读取配置文件。指定的文件必须存在，默认位置的文件不存在时使用默认值
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => match Self::default_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| err_msg(format!("Failed to read {}: {}", path.display(), e)))?;
+        let mut settings: Self = toml::from_str(&contents)
+            .map_err(|e| err_msg(format!("Invalid config {}: {}", path.display(), e)))?;
+        settings.output_dir = expand_home(&settings.output_dir);
+        settings.cache_dir = expand_home(&settings.cache_dir);
+        settings.data_dir = expand_home(&settings.data_dir);
+        Ok(settings)
+    }
+
+    pub fn client_options(&self) -> ClientOptions {
+        let mut retry = RetryPolicy::default();
+        if let Some(max_attempts) = self.max_attempts {
+            retry.max_attempts = max_attempts;
+        }
+        ClientOptions {
+            connect_timeout: self.connect_timeout.map(Duration::from_secs),
+            read_timeout: Some(Duration::from_secs(self.timeout)),
+            user_agent: self.user_agent.clone(),
+            proxy: self.proxy.clone(),
+            accept_invalid_certs: self.insecure,
+            retry,
+        }
+    }
+
+    pub fn export_options(&self) -> exporters::Options {
+        exporters::Options {
+            volume: self.volume.clone(),
+            rtl: self.rtl,
+            output_dir: self.output_dir.clone(),
+            cache_dir: self.cache_dir.clone(),
+            filename: self.filename.clone(),
+            platform_filenames: self
+                .platforms
+                .iter()
+                .filter_map(|(domain, platform)| {
+                    platform
+                        .filename
+                        .as_ref()
+                        .map(|filename| (domain.clone(), filename.clone()))
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// 链接所属平台的设置
+    pub fn platform(&self, url: &str) -> Option<&PlatformSettings> {
+        let url = Url::parse(url).ok()?;
+        self.platform_for_host(url.host_str()?)
+    }
+
+    pub fn platform_for_host(&self, host: &str) -> Option<&PlatformSettings> {
+        match_domain(&self.platforms, host)
+    }
+
+    /// 在 headers 的基础上附加链接所属平台的请求头
+    pub fn headers_for(
+        &self,
+        url: &str,
+        headers: &HashMap<String, String>,
+    ) -> HashMap<String, String> {
+        let mut headers = headers.clone();
+        if let Some(platform) = self.platform(url) {
+            headers.extend(platform.headers.clone());
+        }
+        headers
+    }
+
+    /// 平台页面请求的限速
+    pub fn rate_limit(&self, host: &str) -> Option<RateLimit> {
+        self.platform_for_host(host)
+            .and_then(|platform| platform.rate_limit)
+            .or(self.rate_limit)
+    }
+
+    /// 下载章节页面的选项，图片请求使用章节所属平台的限速
+    pub fn download_options(
+        &self,
+        chapter_url: &str,
+        headers: &HashMap<String, String>,
+    ) -> DownloadOptions {
+        DownloadOptions {
+            jobs: self.jobs,
+            headers: self.headers_for(chapter_url, headers),
+            rate_limit: self
+                .platform(chapter_url)
+                .and_then(|platform| platform.image_rate_limit)
+                .or(self.image_rate_limit),
+        }
+    }
+}
+
+/// 主机名属于该域名或其子域名
+pub fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+/// 按主机名查找以域名为键的设置，完全匹配优先，其次是最长的上级域名
+pub fn match_domain<'a, V>(map: &'a HashMap<String, V>, host: &str) -> Option<&'a V> {
+    map.get(host).or_else(|| {
+        map.iter()
+            .filter(|(domain, _)| domain_matches(host, domain))
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(_, value)| value)
+    })
+}
+
+/// 默认的缓存目录（eg: ~/.cache/mikack-cli）
+pub fn default_cache_dir() -> PathBuf {
+    match dirs::cache_dir() {
+        Some(dir) => dir.join("mikack-cli"),
+        None => PathBuf::from(CACHE_DIR),
+    }
+}
+
+/// 默认的数据目录（eg: ~/.local/share/mikack-cli）
+pub fn default_data_dir() -> PathBuf {
+    match dirs::data_dir() {
+        Some(dir) => dir.join("mikack-cli"),
+        None => PathBuf::from(DATA_DIR),
+    }
+}
+
+/// 默认的输出目录（eg: ~/Downloads/mikack-cli）
+pub fn default_output_dir() -> PathBuf {
+    match dirs::download_dir() {
+        Some(dir) => dir.join("mikack-cli"),
+        None => PathBuf::from(OUTPUT_DIR),
+    }
+}
+
+/// 展开路径开头的 `~`
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_domain() {
+        let map = [
+            "example.com",
+            "m.example.com",
+            "a.m.example.com",
+            "other.com",
+        ]
+        .iter()
+        .map(|domain| (domain.to_string(), domain.to_string()))
+        .collect::<HashMap<_, _>>();
+        let matched = |host: &str| match_domain(&map, host).map(|v| v.as_str());
+        assert_eq!(matched("example.com"), Some("example.com"));
+        assert_eq!(matched("m.example.com"), Some("m.example.com"));
+        assert_eq!(matched("www.example.com"), Some("example.com"));
+        assert_eq!(matched("x.m.example.com"), Some("m.example.com"));
+        assert_eq!(matched("b.a.m.example.com"), Some("a.m.example.com"));
+        assert_eq!(matched("notexample.com"), None);
+        assert_eq!(matched("example.org"), None);
+    }
+
+    #[test]
+    fn test_platform_for_host() {
+        let mut settings = Settings::default();
+        for (domain, filename) in &[("example.com", "{title}"), ("m.example.com", "m-{title}")] {
+            let mut platform = PlatformSettings::default();
+            platform.filename = Some(filename.to_string());
+            settings.platforms.insert(domain.to_string(), platform);
+        }
+        let filename = |host: &str| {
+            settings
+                .platform_for_host(host)
+                .and_then(|platform| platform.filename.as_deref())
+        };
+        assert_eq!(filename("img.m.example.com"), Some("m-{title}"));
+        assert_eq!(filename("www.example.com"), Some("{title}"));
+        assert_eq!(filename("example.org"), None);
+    }
+}
@@ -0,0 +1,441 @@
+use crate::downloader::{Failure, Progress, Session};
+use crate::history::{self, History};
+use crate::library::Library;
+use crate::queue::{Job, Queue};
+use crate::settings::Settings;
+use crate::{adopt_cached_chapter, cache_metadata, chapter_cache_dir, exporters, platform, Origin};
+use chrono::offset::Utc;
+use mikack::error::*;
+use mikack::extractors::{self, DomainRoute, Extractor};
+use mikack::models::{Chapter, Comic, Page};
+use std::path::{Path, PathBuf};
+
+pub type ExtractorObject = Box<dyn Extractor + Sync + Send>;
+
+/// 链接指向的漫画主页或章节
+pub enum Target {
+    Comic(Comic),
+    Chapter(Chapter),
+}
+
+pub fn get_exrt(domain: String) -> Result<&'static ExtractorObject> {
+    if let Some(extractor) = extractors::get_extr(&domain) {
+        Ok(extractor)
+    } else {
+        Err(err_msg(format!("Unsupported platform {}", domain)))
+    }
+}
+
+/// 识别链接所属的平台及类型
+pub fn resolve(url: &str) -> Result<(&'static ExtractorObject, Target)> {
+    match extractors::domain_route(url) {
+        Some(DomainRoute::Comic(domain)) => {
+            Ok((get_exrt(domain)?, Target::Comic(Comic::new("", url))))
+        }
+        Some(DomainRoute::Chapter(domain)) => {
+            Ok((get_exrt(domain)?, Target::Chapter(Chapter::new("", url, 0))))
+        }
+        None => Err(err_msg("This link is not supported")),
+    }
+}
+
+/// 请求平台的页面（漫画列表、章节列表及阅读页等）前按限速等待
+pub fn throttle(session: &Session, settings: &Settings, host: &str) {
+    session.throttle(host, settings.rate_limit(host).as_ref());
+}
+
+/// 获取漫画的章节列表，请求前按限速等待
+pub fn fetch_chapters(
+    session: &Session,
+    settings: &Settings,
+    extractor: &ExtractorObject,
+    comic: &mut Comic,
+) -> Result<()> {
+    throttle(session, settings, &platform(&comic.url));
+    Ok(extractor.fetch_chapters(comic)?)
+}
+
+/// 章节在漫画中的来源，n 为章节序号（从 1 开始）
+pub fn chapter_origin(comic: &Comic, n: usize) -> Origin {
+    Origin {
+        comic_title: Some(comic.title.clone()),
+        comic_url: Some(comic.url.clone()),
+        index: Some(n),
+    }
+}
+
+/// 获取订阅漫画的最新章节列表，返回漫画及其新章节的序号。
+/// 单个漫画获取失败时对应的结果为错误，不影响其它漫画
+pub fn check_followed(
+    session: &Session,
+    settings: &Settings,
+) -> Result<Vec<(Comic, Result<Vec<usize>>)>> {
+    let mut library = Library::load(&settings.data_dir)?;
+    let history = History::open(&settings.data_dir).entries()?;
+    let mut updates = vec![];
+    for subscription in &mut library.comics {
+        let mut comic = Comic::new(&subscription.title, &subscription.url);
+        let fetched = match extractors::domain_route(&subscription.url) {
+            Some(DomainRoute::Comic(domain)) => get_exrt(domain)
+                .and_then(|extractor| fetch_chapters(session, settings, extractor, &mut comic)),
+            _ => Err(err_msg("This link is not supported")),
+        };
+        let new_chapters = fetched.map(|()| {
+            subscription.checked_at = Some(Utc::now().to_rfc3339());
+            subscription.new_chapters(&comic, &history)
+        });
+        updates.push((comic, new_chapters));
+    }
+    library.save(&settings.data_dir)?;
+    Ok(updates)
+}
+
+/// 将漫画中选中的章节（序号从 1 开始）加入队列，以当前的保存格式导出，返回任务编号。
+/// 设置了卷名时，这些章节全部完成后合并导出为一卷
+pub fn enqueue_chapters(
+    settings: &Settings,
+    comic: &Comic,
+    selects: &[usize],
+    export: bool,
+) -> Result<Vec<u64>> {
+    Queue::update(&settings.data_dir, |queue| {
+        selects
+            .iter()
+            .map(|n| {
+                let chapter = &comic.chapters[n - 1];
+                queue.push(
+                    &chapter.url,
+                    &chapter.title,
+                    &chapter_origin(comic, *n),
+                    export,
+                    settings.format.clone(),
+                    settings.volume.clone().filter(|_| export),
+                )
+            })
+            .collect()
+    })
+}
+
+/// 执行完成的队列任务
+pub struct Done {
+    pub saved: Saved,
+    /// 导出的格式及文件路径，任务不需要导出时为空
+    pub outputs: Vec<(String, PathBuf)>,
+}
+
+/// 执行队列中的一个任务：下载章节，完整下载后记录到订阅列表，
+/// 需要时以加入队列时的格式导出（属于某一卷的章节留到整卷完成后合并导出）
+pub fn run_job(
+    session: &Session,
+    settings: &Settings,
+    job: &Job,
+    progress: &mut dyn Progress,
+) -> Result<Done> {
+    let (extractor, _) = resolve(&job.url)?;
+    let mut chapter = Chapter::new(&job.title, &job.url, 0);
+    let saved = save_chapter(
+        session,
+        settings,
+        extractor,
+        &mut chapter,
+        &job.origin,
+        progress,
+    )?;
+    saved.check()?;
+    if let Some(comic_url) = &job.origin.comic_url {
+        mark_downloaded(&settings.data_dir, comic_url, &job.url)?;
+    }
+    let mut outputs = vec![];
+    if job.export && job.volume.is_none() {
+        let settings = Settings {
+            format: job.format.clone(),
+            volume: None,
+            ..settings.clone()
+        };
+        for format in formats(&settings) {
+            let path = export_chapters(&settings, &[saved.base_dir.as_str()], &format)?;
+            outputs.push((format, path));
+        }
+    }
+    Ok(Done { saved, outputs })
+}
+
+/// 将一卷的全部章节按任务的格式合并导出
+fn export_volume(
+    settings: &Settings,
+    job: &Job,
+    base_dirs: &[String],
+) -> Result<Vec<(String, PathBuf)>> {
+    let settings = Settings {
+        format: job.format.clone(),
+        volume: job.volume.clone(),
+        ..settings.clone()
+    };
+    let base_dirs = base_dirs.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    let mut outputs = vec![];
+    for format in formats(&settings) {
+        let path = export_chapters(&settings, &base_dirs, &format)?;
+        outputs.push((format, path));
+    }
+    Ok(outputs)
+}
+
+/// 依次执行队列中等待的任务（ids 为空时不限），单个任务失败不影响其它任务。
+/// 每个任务开始时由 start 创建进度回调，结束后将结果交给 finish，返回失败的任务数量。
+/// 一卷的最后一个章节完成时合并导出整卷，导出的文件计入该任务的结果
+pub fn run_queue<P, S, F>(
+    session: &Session,
+    settings: &Settings,
+    ids: Option<&[u64]>,
+    mut start: S,
+    mut finish: F,
+) -> Result<usize>
+where
+    P: Progress,
+    S: FnMut(&Job) -> P,
+    F: FnMut(&Job, &Result<Done>),
+{
+    let mut failed = 0;
+    while let Some(job) = Queue::update(&settings.data_dir, |queue| queue.start_next(ids))? {
+        let mut progress = start(&job);
+        let mut result = run_job(session, settings, &job, &mut progress);
+        drop(progress);
+        let saved = result
+            .as_ref()
+            .map(|done| done.saved.base_dir.clone())
+            .map_err(|e| e.to_string());
+        let merge = Queue::update(&settings.data_dir, |queue| {
+            let completed = saved.is_ok();
+            queue.finish(job.id, saved);
+            match &job.volume {
+                Some(volume) if completed => queue.take_volume(volume),
+                _ => None,
+            }
+        })?;
+        if let Some(base_dirs) = merge {
+            result = result.and_then(|mut done| {
+                done.outputs
+                    .extend(export_volume(settings, &job, &base_dirs)?);
+                Ok(done)
+            });
+        }
+        if result.is_err() {
+            failed += 1;
+        }
+        finish(&job, &result);
+    }
+    Ok(failed)
+}
+
+/// 一个章节的保存结果
+pub struct Saved {
+    /// 章节的缓存目录（相对于缓存根目录）
+    pub base_dir: String,
+    pub total: usize,
+    /// 直接使用缓存的页面数量
+    pub cached: usize,
+    pub failures: Vec<Failure>,
+}
+
+impl Saved {
+    /// 存在下载失败的页面时返回错误
+    pub fn check(&self) -> Result<()> {
+        if self.failures.is_empty() {
+            return Ok(());
+        }
+        Err(err_msg(format!(
+            "{} of {} pages failed to download, run again to resume",
+            self.failures.len(),
+            self.total
+        )))
+    }
+}
+
+/// 下载章节的全部页面到缓存并写入元数据，完整下载后记录到下载历史。
+/// 单个页面失败不会中断下载，失败的页面记录在返回结果中
+pub fn save_chapter(
+    session: &Session,
+    settings: &Settings,
+    extractor: &ExtractorObject,
+    chapter: &mut Chapter,
+    origin: &Origin,
+    progress: &mut dyn Progress,
+) -> Result<Saved> {
+    let url = chapter.url.clone();
+    let options = settings.download_options(&url, &chapter.page_headers);
+    let host = platform(&url);
+    throttle(session, settings, &host);
+    let mut pages_iter = extractor.pages_iter(chapter)?;
+    if let Some(comic_url) = &origin.comic_url {
+        adopt_cached_chapter(&settings.cache_dir, comic_url, &url)?;
+    }
+    let base_dir = chapter_cache_dir(origin.comic_url.as_deref(), &url)?;
+    let title = pages_iter.chapter_title_clone();
+    let total = pages_iter.total as usize;
+    // 上次运行时已下载完成的页码，覆盖元数据之前读取
+    let finished = exporters::read_metadata(&settings.cache_dir, &base_dir)
+        .map(|metadata| metadata.finished)
+        .unwrap_or_default();
+    // 先记录全部页面为未完成，下载中断时也能知道哪些页面尚未完成
+    let mut recorder = Recorder {
+        progress,
+        cache_dir: &settings.cache_dir,
+        base_dir: &base_dir,
+        chapter: Chapter::new(&title, &url, 0),
+        origin,
+        total,
+        finished: vec![],
+    };
+    recorder.save()?;
+    recorder.start(&title, &base_dir, total);
+    // 部分平台在解析每一页的图片地址时都会请求阅读页，解析尚未下载的页面前同样按页面限速等待，
+    // 已缓存的页面不需要再请求图片，不限速
+    let limit = settings.rate_limit(&host);
+    let mut n = 0;
+    let pages = std::iter::from_fn(move || {
+        n += 1;
+        if !finished.contains(&n) {
+            session.throttle(&host, limit.as_ref());
+        }
+        pages_iter.next()
+    });
+    let download = session.download_pages(
+        pages,
+        &settings.cache_dir,
+        &base_dir,
+        &options,
+        &mut recorder,
+    )?;
+    let pending = download.pending();
+    let total = download.pages.len();
+    // 与下载过程中写入的元数据一致，只记录下载完成的页面
+    chapter.pages = download
+        .pages
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !pending.contains(&(index + 1)))
+        .map(|(_, page)| page)
+        .collect();
+    if chapter.title.is_empty() {
+        chapter.title = title;
+    }
+    cache_metadata(
+        &settings.cache_dir,
+        &base_dir,
+        chapter,
+        origin,
+        total,
+        &pending,
+    )?;
+    if download.failures.is_empty() {
+        let mut entry = history::Entry::new(chapter, origin);
+        entry.size = history::path_size(&settings.cache_dir.join(&base_dir));
+        History::open(&settings.data_dir).append(&entry)?;
+    }
+    Ok(Saved {
+        base_dir,
+        total,
+        cached: download.cached,
+        failures: download.failures,
+    })
+}
+
+/// 下载过程中随页面完成更新 metadata.json，再转发给原有的进度回调
+struct Recorder<'a> {
+    progress: &'a mut dyn Progress,
+    cache_dir: &'a Path,
+    base_dir: &'a str,
+    chapter: Chapter,
+    origin: &'a Origin,
+    total: usize,
+    /// 已下载完成的页面及其位置
+    finished: Vec<(usize, Page)>,
+}
+
+impl Recorder<'_> {
+    fn save(&mut self) -> Result<()> {
+        self.finished.sort_by_key(|(index, _)| *index);
+        self.chapter.pages = self.finished.iter().map(|(_, page)| page.clone()).collect();
+        let pending = (1..=self.total)
+            .filter(|n| !self.finished.iter().any(|(index, _)| index + 1 == *n))
+            .collect::<Vec<_>>();
+        cache_metadata(
+            self.cache_dir,
+            self.base_dir,
+            &self.chapter,
+            self.origin,
+            self.total,
+            &pending,
+        )
+    }
+}
+
+impl Progress for Recorder<'_> {
+    fn start(&mut self, title: &str, base_dir: &str, total: usize) {
+        self.progress.start(title, base_dir, total);
+    }
+
+    fn page(&mut self, index: usize, page: &Page, result: &Result<bool>) {
+        if result.is_ok() {
+            self.finished.push((index, page.clone()));
+        }
+        // 元数据写入失败不影响下载，下载结束后会再次写入
+        let _ = self.save();
+        self.progress.page(index, page, result);
+    }
+}
+
+/// 将缓存的章节导出为指定格式并记录到下载历史，返回输出路径。
+/// 设置了卷名时多个章节合并为一卷
+pub fn export_chapters(settings: &Settings, base_dirs: &[&str], format: &str) -> Result<PathBuf> {
+    let chapters = base_dirs
+        .iter()
+        .map(|base_dir| exporters::read_metadata(&settings.cache_dir, base_dir))
+        .collect::<Result<Vec<_>>>()?;
+    let history = History::open(&settings.data_dir);
+    // 重复导出相同的章节时覆盖原有的文件，其它章节的同名输出不会被覆盖
+    let mut options = settings.export_options();
+    options.replaceable = history
+        .entries()?
+        .into_iter()
+        .filter(|entry| {
+            chapters
+                .iter()
+                .any(|metadata| metadata.chapter.url == entry.chapter_url)
+        })
+        .filter_map(|entry| entry.output.map(PathBuf::from))
+        .collect();
+    let exporter = exporters::gen_expo(format, base_dirs, &options)?;
+    let path = exporter.expo()?;
+    let size = history::path_size(&path);
+    for metadata in &chapters {
+        let mut entry = history::Entry::new(&metadata.chapter, &metadata.origin);
+        entry.format = Some(format.to_string());
+        entry.output = Some(path.display().to_string());
+        entry.size = size;
+        history.append(&entry)?;
+    }
+    Ok(path)
+}
+
+/// 保存格式列表，未设置时为 `none`（仅复制图片）
+pub fn formats(settings: &Settings) -> Vec<String> {
+    settings
+        .format
+        .as_deref()
+        .unwrap_or("none")
+        .split(',')
+        .map(|format| format.trim().to_string())
+        .filter(|format| !format.is_empty())
+        .collect()
+}
+
+/// 在订阅列表中记录已下载的章节（漫画未订阅时忽略）
+pub fn mark_downloaded(data_dir: &Path, comic_url: &str, chapter_url: &str) -> Result<()> {
+    let mut library = Library::load(data_dir)?;
+    if let Some(subscription) = library.get_mut(comic_url) {
+        subscription.mark_downloaded(chapter_url);
+        library.save(data_dir)?;
+    }
+    Ok(())
+}